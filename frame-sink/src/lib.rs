@@ -0,0 +1,84 @@
+//! Frame sinks are where renderers publish the frames they draw, so that the
+//! rendering doesn't depend on how the frames are shown.
+//!
+//! Besides the Syphon outputs the renderers implement on macOS, this crate
+//! provides sinks that work anywhere: [`PngDirectory`] writes every frame into
+//! a directory and [`SharedMemoryRing`] hands frames to other local processes
+//! through POSIX shared memory.
+
+use std::io;
+use std::time::SystemTime;
+
+mod png_directory;
+#[cfg(unix)]
+mod shared_memory;
+
+pub use png_directory::PngDirectory;
+#[cfg(unix)]
+pub use shared_memory::SharedMemoryRing;
+
+/// A destination for rendered frames.
+pub trait FrameSink {
+    /// Publishes a frame, replacing the previously published one.
+    fn publish(&mut self, frame: &Frame) -> io::Result<()>;
+}
+
+impl<T: FrameSink + ?Sized> FrameSink for Box<T> {
+    fn publish(&mut self, frame: &Frame) -> io::Result<()> {
+        (**self).publish(frame)
+    }
+}
+
+/// A rendered frame in 8-bit RGBA with premultiplied alpha, which is what
+/// tiny-skia and Skia render into.
+#[derive(Debug, Copy, Clone)]
+pub struct Frame<'a> {
+    /// The width in pixels.
+    pub width: u32,
+    /// The height in pixels.
+    pub height: u32,
+    /// The number of bytes between the starts of two rows, at least
+    /// `4 * width`.
+    pub stride: usize,
+    /// When the frame was rendered.
+    pub timestamp: SystemTime,
+    /// The pixel rows, `stride * height` bytes in total.
+    pub data: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Creates a frame from tightly packed rows rendered right now.
+    ///
+    /// Panics if `data` doesn't hold exactly `width * height` pixels.
+    pub fn new(width: u32, height: u32, data: &'a [u8]) -> Self {
+        let stride = 4 * width as usize;
+        assert_eq!(data.len(), stride * height as usize, "frame size mismatch");
+        Self {
+            width,
+            height,
+            stride,
+            timestamp: SystemTime::now(),
+            data,
+        }
+    }
+
+    /// Checks that the rows fit into the data, since the fields can be set
+    /// without going through [`Frame::new`]. Returns the number of bytes the
+    /// rows span.
+    pub fn check(&self) -> io::Result<usize> {
+        let invalid = |message| Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        if self.stride < 4 * self.width as usize {
+            return invalid("frame stride is shorter than a row");
+        }
+        match self.stride.checked_mul(self.height as usize) {
+            Some(len) if len <= self.data.len() => Ok(len),
+            _ => invalid("frame data is shorter than its rows"),
+        }
+    }
+
+    /// Returns the pixel row with the given index, without any padding.
+    pub fn row(&self, y: u32) -> &'a [u8] {
+        let start = y as usize * self.stride;
+        &self.data[start..start + 4 * self.width as usize]
+    }
+}
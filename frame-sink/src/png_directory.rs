@@ -0,0 +1,184 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+
+use crate::{Frame, FrameSink};
+
+/// Writes every published frame as a numbered PNG file into a directory.
+///
+/// Files are named `<prefix><sequence>.png` with a zero-padded sequence
+/// number starting at one. They are written to a temporary file first and
+/// then renamed, so readers never see a partially written image. With
+/// [`PngDirectory::keep_last`], older files are removed as new ones are
+/// written.
+#[derive(Debug)]
+pub struct PngDirectory {
+    /// The directory the files are written to.
+    dir: PathBuf,
+    /// Prepended to the sequence number in file names.
+    prefix: String,
+    /// The sequence number of the last written frame.
+    sequence: u64,
+    /// How many of the newest files are kept, if not all of them.
+    keep: Option<NonZeroU64>,
+}
+
+impl PngDirectory {
+    /// Creates the directory if necessary and starts a new sequence in it.
+    pub fn new(dir: impl Into<PathBuf>, prefix: impl Into<String>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            prefix: prefix.into(),
+            sequence: 0,
+            keep: None,
+        })
+    }
+
+    /// Keeps only the given number of the newest files, so that the
+    /// directory doesn't grow without bound while frames are published.
+    pub fn keep_last(mut self, count: NonZeroU64) -> Self {
+        self.keep = Some(count);
+        self
+    }
+
+    /// The directory the files are written to.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The path of the most recently written file, if any.
+    pub fn last_path(&self) -> Option<PathBuf> {
+        (self.sequence > 0).then(|| self.path(self.sequence))
+    }
+
+    fn path(&self, sequence: u64) -> PathBuf {
+        self.dir.join(format!("{}{sequence:06}.png", self.prefix))
+    }
+}
+
+impl FrameSink for PngDirectory {
+    fn publish(&mut self, frame: &Frame) -> io::Result<()> {
+        frame.check()?;
+        let sequence = self.sequence + 1;
+        let path = self.path(sequence);
+        let partial = path.with_extension("png.partial");
+
+        write_png(&partial, frame)?;
+        fs::rename(&partial, &path)?;
+
+        if let Some(keep) = self.keep {
+            if let Some(oldest) = sequence.checked_sub(keep.get()).filter(|&s| s > 0) {
+                match fs::remove_file(self.path(oldest)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+        }
+
+        self.sequence = sequence;
+        Ok(())
+    }
+}
+
+/// Encodes a frame as a PNG file, which stores straight alpha.
+fn write_png(path: &Path, frame: &Frame) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(File::create(path)?),
+        frame.width,
+        frame.height,
+    );
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut data = Vec::with_capacity(4 * frame.width as usize * frame.height as usize);
+    for y in 0..frame.height {
+        for pixel in frame.row(y).chunks_exact(4) {
+            data.extend_from_slice(&demultiply(pixel));
+        }
+    }
+
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&data))
+        .map_err(io::Error::other)
+}
+
+/// Converts a premultiplied pixel to straight alpha.
+fn demultiply(pixel: &[u8]) -> [u8; 4] {
+    let alpha = pixel[3];
+    let channel = |value: u8| match alpha {
+        0 => 0,
+        255 => value,
+        _ => ((value as u32 * 255 + alpha as u32 / 2) / alpha as u32).min(255) as u8,
+    };
+    [
+        channel(pixel[0]),
+        channel(pixel[1]),
+        channel(pixel[2]),
+        alpha,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_numbered_files_with_straight_alpha() {
+        let dir = std::env::temp_dir().join(format!("frame-sink-png-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut sink = PngDirectory::new(&dir, "frame-").unwrap();
+        assert_eq!(sink.last_path(), None);
+
+        // Opaque red, half-transparent premultiplied white, transparent.
+        let pixels = [
+            255, 0, 0, 255, 128, 128, 128, 128, 0, 0, 0, 0, 10, 20, 30, 40,
+        ];
+        sink.publish(&Frame::new(2, 2, &pixels)).unwrap();
+        sink.publish(&Frame::new(2, 2, &pixels)).unwrap();
+
+        let path = sink.last_path().unwrap();
+        assert_eq!(path, dir.join("frame-000002.png"));
+        assert!(dir.join("frame-000001.png").is_file());
+        assert!(!path.with_extension("png.partial").exists());
+
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+        assert_eq!((info.width, info.height), (2, 2));
+        assert_eq!(info.color_type, png::ColorType::Rgba);
+        assert_eq!(
+            data,
+            [255, 0, 0, 255, 255, 255, 255, 128, 0, 0, 0, 0, 64, 128, 191, 40]
+        );
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn keeps_only_the_newest_files() {
+        let dir = std::env::temp_dir().join(format!("frame-sink-keep-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let mut sink = PngDirectory::new(&dir, "")
+            .unwrap()
+            .keep_last(NonZeroU64::new(2).unwrap());
+
+        let pixels = [0; 4];
+        for _ in 0..5 {
+            sink.publish(&Frame::new(1, 1, &pixels)).unwrap();
+        }
+
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["000004.png", "000005.png"]);
+
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,347 @@
+//! A ring buffer of frames in POSIX shared memory.
+//!
+//! The segment starts with a 64 byte header, followed by the slots. All
+//! integers are in native byte order.
+//!
+//! | Offset | Type      | Field                                              |
+//! |--------|-----------|----------------------------------------------------|
+//! | 0      | `[u8; 8]` | magic, `FRAMERNG`                                  |
+//! | 8      | `u32`     | layout version, currently 1                        |
+//! | 12     | `u32`     | number of slots                                    |
+//! | 16     | `u64`     | pixel bytes per slot                               |
+//! | 24     | `u64`     | sequence number of the newest frame, 0 if none yet |
+//! | 32     | `u32`     | 1 once the segment was abandoned, 0 otherwise      |
+//!
+//! Every slot starts with a 32 byte header, followed by the pixel data in
+//! 8-bit premultiplied RGBA:
+//!
+//! | Offset | Type  | Field                                                   |
+//! |--------|-------|---------------------------------------------------------|
+//! | 0      | `u64` | sequence number of the frame, 0 while it's written      |
+//! | 8      | `u32` | width in pixels                                         |
+//! | 12     | `u32` | height in pixels                                        |
+//! | 16     | `u32` | bytes per row                                           |
+//! | 24     | `u64` | timestamp in nanoseconds since the UNIX epoch           |
+//!
+//! Frame `n` is stored in slot `(n - 1) % slots`. To read the newest frame,
+//! load its sequence number from the header and the slot's sequence number
+//! with acquire ordering, copy the slot, issue an acquire fence and check
+//! that the slot's sequence number still matches; otherwise the writer caught
+//! up and the copy has to be retried. Without the fence, the copy may observe
+//! writes of the next frame before the check does. When a frame doesn't fit into
+//! the slots anymore, the writer abandons the segment and creates a larger
+//! one under the same name, so readers should open the name again once the
+//! abandoned flag is set.
+
+use std::ffi::CString;
+use std::io;
+use std::ptr::{self, NonNull};
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::time::UNIX_EPOCH;
+
+use crate::{Frame, FrameSink};
+
+/// Identifies the segment layout.
+const MAGIC: [u8; 8] = *b"FRAMERNG";
+
+/// The version of the segment layout.
+const VERSION: u32 = 1;
+
+/// The size of the segment header.
+const HEADER_SIZE: usize = 64;
+
+/// The size of a slot header.
+const SLOT_HEADER_SIZE: usize = 32;
+
+/// Publishes frames into a ring buffer in POSIX shared memory that other local
+/// processes can map and read.
+pub struct SharedMemoryRing {
+    /// The name of the segment, e.g. `/typst-render`.
+    name: CString,
+    /// The number of slots in the ring.
+    slots: u32,
+    /// The currently mapped segment.
+    segment: Segment,
+    /// The sequence number of the last published frame.
+    sequence: u64,
+}
+
+// SAFETY: The mapping is exclusively owned by the ring and only written
+// through `&mut self`.
+unsafe impl Send for SharedMemoryRing {}
+
+impl SharedMemoryRing {
+    /// Creates a segment with the given name and number of slots, replacing
+    /// any existing segment with that name.
+    ///
+    /// The slots grow as needed, so `capacity`, the number of pixel bytes per
+    /// slot, only has to be a first guess.
+    pub fn new(name: &str, slots: u32, capacity: usize) -> io::Result<Self> {
+        if slots < 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a ring buffer needs at least two slots",
+            ));
+        }
+
+        let name = CString::new(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains a nul byte"))?;
+        let segment = Segment::create(&name, slots, capacity)?;
+
+        Ok(Self {
+            name,
+            slots,
+            segment,
+            sequence: 0,
+        })
+    }
+}
+
+impl FrameSink for SharedMemoryRing {
+    fn publish(&mut self, frame: &Frame) -> io::Result<()> {
+        let len = frame.check()?;
+        if u32::try_from(frame.stride).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame stride doesn't fit into the slot header",
+            ));
+        }
+        if len > self.segment.capacity {
+            self.segment.abandon();
+            self.segment = Segment::create(&self.name, self.slots, len)?;
+        }
+
+        let sequence = self.sequence + 1;
+        let slot = ((sequence - 1) % self.slots as u64) as usize;
+        let timestamp = frame
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+
+        // SAFETY: The slot lies within the mapping, the frame fits into it and
+        // the frame's data holds at least `len` bytes.
+        unsafe {
+            let base = self.segment.slot(slot);
+            let slot_sequence = &*(base as *const AtomicU64);
+            slot_sequence.store(0, Ordering::Relaxed);
+            // Keep the writes below from becoming visible before the slot is
+            // marked as being written, so that readers can't accept a torn
+            // frame under the old sequence number.
+            fence(Ordering::Release);
+
+            ptr::write_unaligned(base.add(8) as *mut u32, frame.width);
+            ptr::write_unaligned(base.add(12) as *mut u32, frame.height);
+            ptr::write_unaligned(base.add(16) as *mut u32, frame.stride as u32);
+            ptr::write_unaligned(base.add(24) as *mut u64, timestamp);
+            ptr::copy_nonoverlapping(frame.data.as_ptr(), base.add(SLOT_HEADER_SIZE), len);
+
+            slot_sequence.store(sequence, Ordering::Release);
+            self.segment.latest().store(sequence, Ordering::Release);
+        }
+
+        self.sequence = sequence;
+        Ok(())
+    }
+}
+
+impl Drop for SharedMemoryRing {
+    fn drop(&mut self) {
+        self.segment.abandon();
+        // SAFETY: The name is a valid C string.
+        unsafe { libc::shm_unlink(self.name.as_ptr()) };
+    }
+}
+
+/// A mapped shared memory segment.
+struct Segment {
+    /// The start of the mapping.
+    ptr: NonNull<u8>,
+    /// The length of the mapping in bytes.
+    len: usize,
+    /// The number of pixel bytes per slot.
+    capacity: usize,
+}
+
+impl Segment {
+    /// Creates and maps a new segment, unlinking an existing one with the
+    /// same name first.
+    fn create(name: &CString, slots: u32, capacity: usize) -> io::Result<Self> {
+        // Keep the slots aligned to cache lines.
+        let capacity = capacity.next_multiple_of(64);
+        let len = HEADER_SIZE + slots as usize * (SLOT_HEADER_SIZE + capacity);
+
+        // SAFETY: Plain POSIX calls with valid arguments. The descriptor is
+        // closed on every path, which keeps the mapping alive.
+        let ptr = unsafe {
+            libc::shm_unlink(name.as_ptr());
+            let fd = libc::shm_open(
+                name.as_ptr(),
+                libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
+                0o644 as libc::c_uint,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            if libc::ftruncate(fd, len as libc::off_t) < 0 {
+                let err = io::Error::last_os_error();
+                libc::close(fd);
+                libc::shm_unlink(name.as_ptr());
+                return Err(err);
+            }
+
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            );
+            libc::close(fd);
+            if ptr == libc::MAP_FAILED {
+                let err = io::Error::last_os_error();
+                libc::shm_unlink(name.as_ptr());
+                return Err(err);
+            }
+
+            ptr as *mut u8
+        };
+
+        // SAFETY: `mmap` succeeded, so the pointer is valid for `len` bytes,
+        // which are zeroed by `ftruncate`.
+        unsafe {
+            ptr::copy_nonoverlapping(MAGIC.as_ptr(), ptr, MAGIC.len());
+            ptr::write_unaligned(ptr.add(8) as *mut u32, VERSION);
+            ptr::write_unaligned(ptr.add(12) as *mut u32, slots);
+            ptr::write_unaligned(ptr.add(16) as *mut u64, capacity as u64);
+        }
+
+        Ok(Self {
+            ptr: NonNull::new(ptr).unwrap(),
+            len,
+            capacity,
+        })
+    }
+
+    /// The sequence number of the newest frame.
+    fn latest(&self) -> &AtomicU64 {
+        // SAFETY: The field lies within the header and is 8-byte aligned
+        // because the mapping is page-aligned.
+        unsafe { &*(self.ptr.as_ptr().add(24) as *const AtomicU64) }
+    }
+
+    /// The start of the slot with the given index.
+    fn slot(&self, index: usize) -> *mut u8 {
+        // SAFETY: The callers only pass indices below the number of slots.
+        unsafe {
+            self.ptr
+                .as_ptr()
+                .add(HEADER_SIZE + index * (SLOT_HEADER_SIZE + self.capacity))
+        }
+    }
+
+    /// Tells readers that the segment won't be written anymore.
+    fn abandon(&self) {
+        // SAFETY: The field lies within the header and is 4-byte aligned.
+        let closed = unsafe { &*(self.ptr.as_ptr().add(32) as *const AtomicU32) };
+        closed.store(1, Ordering::Release);
+    }
+}
+
+impl Drop for Segment {
+    fn drop(&mut self) {
+        // SAFETY: The mapping was created with exactly this length.
+        unsafe { libc::munmap(self.ptr.as_ptr() as *mut libc::c_void, self.len) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use super::*;
+
+    #[test]
+    fn readers_see_published_frames() {
+        let name = format!("/frame-sink-test-{}", std::process::id());
+        let mut ring = SharedMemoryRing::new(&name, 2, 16).unwrap();
+
+        let first: Vec<u8> = (0..16).collect();
+        let second: Vec<u8> = (0..36).map(|i| 255 - i).collect();
+        ring.publish(&Frame::new(2, 2, &first)).unwrap();
+        assert_eq!(read_latest(&name), Some((1, 2, 2, first)));
+
+        // The second frame doesn't fit, so the segment is replaced.
+        ring.publish(&Frame::new(3, 3, &second)).unwrap();
+        assert_eq!(read_latest(&name), Some((2, 3, 3, second)));
+    }
+
+    #[test]
+    fn rejects_frames_shorter_than_their_rows() {
+        let name = format!("/frame-sink-test-short-{}", std::process::id());
+        let mut ring = SharedMemoryRing::new(&name, 2, 16).unwrap();
+        let data = [0; 16];
+
+        let mut frame = Frame::new(2, 2, &data);
+        frame.height = 1000;
+        assert!(ring.publish(&frame).is_err());
+
+        let mut frame = Frame::new(2, 2, &data);
+        frame.stride = 4;
+        assert!(ring.publish(&frame).is_err());
+
+        assert_eq!(read_latest(&name), None);
+    }
+
+    /// Maps the segment like a separate reader process would and copies the
+    /// newest frame, following the protocol described in the module docs.
+    fn read_latest(name: &str) -> Option<(u64, u32, u32, Vec<u8>)> {
+        let name = CString::new(name).unwrap();
+        unsafe {
+            let fd = libc::shm_open(name.as_ptr(), libc::O_RDONLY, 0);
+            assert!(fd >= 0, "failed to open segment");
+            let mut stat = std::mem::zeroed::<libc::stat>();
+            assert_eq!(libc::fstat(fd, &mut stat), 0);
+            let len = stat.st_size as usize;
+            let ptr = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                fd,
+                0,
+            );
+            libc::close(fd);
+            assert_ne!(ptr, libc::MAP_FAILED);
+            let base = ptr as *const u8;
+
+            assert_eq!(slice::from_raw_parts(base, 8), &MAGIC);
+            let slots = ptr::read_unaligned(base.add(12) as *const u32) as u64;
+            let capacity = ptr::read_unaligned(base.add(16) as *const u64) as usize;
+            let latest = (*(base.add(24) as *const AtomicU64)).load(Ordering::Acquire);
+
+            let result = (latest > 0).then(|| {
+                let index = ((latest - 1) % slots) as usize;
+                let slot = base.add(HEADER_SIZE + index * (SLOT_HEADER_SIZE + capacity));
+                let sequence = &*(slot as *const AtomicU64);
+                assert_eq!(sequence.load(Ordering::Acquire), latest);
+
+                let width = ptr::read_unaligned(slot.add(8) as *const u32);
+                let height = ptr::read_unaligned(slot.add(12) as *const u32);
+                let stride = ptr::read_unaligned(slot.add(16) as *const u32) as usize;
+                let data =
+                    slice::from_raw_parts(slot.add(SLOT_HEADER_SIZE), stride * height as usize)
+                        .to_vec();
+
+                fence(Ordering::Acquire);
+                assert_eq!(sequence.load(Ordering::Relaxed), latest);
+                (latest, width, height, data)
+            });
+
+            libc::munmap(ptr, len);
+            result
+        }
+    }
+}
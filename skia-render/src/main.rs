@@ -1,79 +1,87 @@
-use std::borrow::BorrowMut;
-use std::ops::Deref;
-use std::{borrow::Borrow, mem};
-
+#[cfg(not(target_os = "macos"))]
+use frame_sink::FrameSink;
 use nannou::prelude::*;
-use objc2::{rc::Retained, runtime::ProtocolObject};
-use objc2_core_foundation::{CGFloat, CGPoint, CGRect, CGSize};
-use objc2_foundation::NSRect;
-use objc2_metal::{MTLCommandQueue, MTLTexture};
-use studio::MetalContext;
-use syphon::metal_server::SyphonMetalServer;
-use wgpu::TextureUsages;
-use wgpu_hal::{api, Device, TextureUses};
+use studio::SkiaDrawing;
 
 mod studio;
 
+/// The size of the window and of the published drawing.
+const WIDTH: u32 = 512;
+const HEIGHT: u32 = 1024;
+
 struct Model {
     _window: WindowId,
-    _syphon_server: Retained<SyphonMetalServer>,
-    _metal_context: MetalContext,
+    drawing: SkiaDrawing,
+    output: Output,
+}
+
+/// Where the drawing is published.
+enum Output {
+    /// Hands the drawing's texture to Syphon without leaving the GPU.
+    #[cfg(target_os = "macos")]
+    Syphon(studio::SkiaStudio),
+    /// Reads the drawing back and publishes it as a frame.
+    #[cfg(not(target_os = "macos"))]
+    Sink(Box<dyn FrameSink>),
 }
 
 fn model(app: &App) -> Model {
     let _window = app
         .new_window()
-        .size(512, 1024)
+        .size(WIDTH, HEIGHT)
         .title("nannou")
         .view(view)
         .event(event)
         .build()
         .unwrap();
-    let _metal_context = MetalContext::new();
-    let _syphon_server =
-        SyphonMetalServer::from_device("Open World Game", &_metal_context.metal_device);
+    let (drawing, output) = open_output(WIDTH as i32, HEIGHT as i32);
 
     Model {
         _window,
-        _syphon_server,
-        _metal_context,
+        drawing,
+        output,
     }
 }
 
-impl Drop for Model {
-    fn drop(&mut self) {
-        self._syphon_server.stop();
-    }
+/// Draws on the GPU and publishes through a Syphon server.
+#[cfg(target_os = "macos")]
+fn open_output(width: i32, height: i32) -> (SkiaDrawing, Output) {
+    let studio = studio::SkiaStudio::new();
+    (studio.create_drawing(width, height), Output::Syphon(studio))
+}
+
+/// Draws in main memory and publishes into a shared memory ring buffer.
+#[cfg(not(target_os = "macos"))]
+fn open_output(width: i32, height: i32) -> (SkiaDrawing, Output) {
+    let capacity = 4 * width as usize * height as usize;
+    let sink = frame_sink::SharedMemoryRing::new("/open-world-game", 3, capacity)
+        .expect("Couldn't create shared memory for frames.");
+    (
+        SkiaDrawing::raster(width, height),
+        Output::Sink(Box::new(sink)),
+    )
 }
 
 fn event(_app: &App, _model: &mut Model, event: WindowEvent) {}
 
-fn view(_app: &App, _model: &Model, frame: Frame) {
-    let wgpu_texture = frame.texture();
-    unsafe {
-        wgpu_texture.as_hal::<api::Metal, _>(|metal_texture| {
-            if let Some(metal_texture) = metal_texture {
-                let metal_texture = metal_texture.raw_handle();
-                let metal_texture: *const ProtocolObject<dyn MTLTexture> =
-                    mem::transmute(metal_texture.as_ref());
+fn update(_app: &App, model: &mut Model, _update: Update) {
+    model.drawing.canvas().clear(skia_safe::Color::BLACK);
+    match &mut model.output {
+        #[cfg(target_os = "macos")]
+        Output::Syphon(studio) => studio.publish_drawing(&mut model.drawing),
+        #[cfg(not(target_os = "macos"))]
+        Output::Sink(sink) => {
+            if let Err(err) = model.drawing.publish_to(sink.as_mut()) {
+                eprintln!("failed to publish frame ({err})");
+            }
+        }
+    }
+}
 
-                _model._syphon_server.publish_frame_texture(
-                    metal_texture,
-                    Retained::as_ptr(&_model._metal_context.command_queue.commandBuffer().unwrap()),
-                    NSRect::new(
-                        CGPoint { x: 0.0, y: 0.0 },
-                        CGSize::new(
-                            CGFloat::from_u32(wgpu_texture.width()).unwrap(),
-                            CGFloat::from_u32(wgpu_texture.height()).unwrap(),
-                        ),
-                    ),
-                    true,
-                );
-            };
-        })
-    };
+fn view(_app: &App, _model: &Model, frame: Frame) {
+    frame.clear(BLACK);
 }
 
 fn main() {
-    nannou::app(model).run();
+    nannou::app(model).update(update).run();
 }
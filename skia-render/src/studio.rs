@@ -1,47 +1,24 @@
 use std::fs;
+#[cfg(not(target_os = "macos"))]
+use std::io;
 
-use objc2::{rc::Retained, runtime::ProtocolObject};
-use objc2_core_foundation::{CGFloat, CGPoint, CGSize};
-use objc2_foundation::NSRect;
-use objc2_metal::{MTLCommandBuffer, MTLCommandQueue, MTLDevice, MTLTexture};
+#[cfg(not(target_os = "macos"))]
+use frame_sink::{Frame, FrameSink};
+#[cfg(target_os = "macos")]
 use skia_safe::{
-    gpu::{
-        direct_contexts,
-        mtl::{self, BackendContext},
-        surfaces, BackendTexture, Budgeted, SurfaceOrigin,
-    },
+    gpu::{surfaces, BackendTexture},
     surface::BackendHandleAccess,
-    Canvas, ColorSpace, ISize, ImageInfo, Surface, SurfaceProps,
 };
-use syphon::metal_server::SyphonMetalServer;
+#[cfg(not(target_os = "macos"))]
+use skia_safe::{surfaces, AlphaType, ColorSpace, ColorType, ISize, ImageInfo};
+use skia_safe::{Canvas, Surface};
 
-pub struct MetalContext {
-    pub metal_device: Retained<ProtocolObject<dyn MTLDevice>>,
-    pub command_queue: Retained<ProtocolObject<dyn MTLCommandQueue>>,
-    pub sk_metal_backend_context: BackendContext,
-}
+/// Draws with Metal and publishes through Syphon.
+#[cfg(target_os = "macos")]
+mod metal;
 
-impl MetalContext {
-    pub fn new() -> Self {
-        let metal_device = objc2_metal::MTLCreateSystemDefaultDevice()
-            .expect("Failed to get default system device.");
-        let command_queue = metal_device
-            .newCommandQueue()
-            .expect("Failed to create a command queue.");
-        let sk_metal_backend_context = unsafe {
-            mtl::BackendContext::new(
-                Retained::as_ptr(&metal_device) as mtl::Handle,
-                Retained::as_ptr(&command_queue) as mtl::Handle,
-            )
-        };
-
-        Self {
-            metal_device,
-            command_queue,
-            sk_metal_backend_context,
-        }
-    }
-}
+#[cfg(target_os = "macos")]
+pub use metal::SkiaStudio;
 
 pub struct SkiaDrawing {
     surface: Surface,
@@ -52,6 +29,16 @@ impl SkiaDrawing {
         Self { surface }
     }
 
+    /// Creates a drawing in main memory, for platforms without a Metal GPU.
+    #[cfg(not(target_os = "macos"))]
+    pub fn raster(width: i32, height: i32) -> Self {
+        let image_info = ImageInfo::new_n32_premul(ISize { width, height }, ColorSpace::new_srgb());
+        let surface =
+            surfaces::raster(&image_info, None, None).expect("Failed to create raster surface.");
+        Self::new(surface)
+    }
+
+    #[cfg(target_os = "macos")]
     pub fn get_texture(&mut self) -> Option<BackendTexture> {
         self.surface.direct_context().unwrap().flush_and_submit();
         surfaces::get_backend_texture(&mut self.surface, BackendHandleAccess::FlushRead)
@@ -76,78 +63,24 @@ impl SkiaDrawing {
             .expect("Couldn't write canvas to image.");
         fs::write(path, data.as_bytes()).expect("Couldn't write image to disk.");
     }
-}
-
-pub struct SkiaStudio {
-    metal_context: MetalContext,
-    syphon_server: Retained<SyphonMetalServer>,
-}
-
-impl SkiaStudio {
-    pub fn new() -> Self {
-        let metal_context = MetalContext::new();
-        let syphon_server =
-            SyphonMetalServer::from_device("Open World Game", &metal_context.metal_device);
-
-        Self {
-            metal_context,
-            syphon_server,
-        }
-    }
 
-    pub fn create_drawing(&self, width: i32, height: i32) -> SkiaDrawing {
-        let image_info = ImageInfo::new_n32_premul(ISize { width, height }, ColorSpace::new_srgb());
-        let surface_props = SurfaceProps::default();
-        let mut sk_metal_context =
-            direct_contexts::make_metal(&self.metal_context.sk_metal_backend_context, None)
-                .expect("Failed to create context on Metal gpu directly.");
-
-        let surface = surfaces::render_target(
-            &mut sk_metal_context,
-            Budgeted::No,
-            &image_info,
-            1,
-            SurfaceOrigin::TopLeft,
-            Some(&surface_props),
-            false,
-            false,
-        )
-        .expect("Failed to create surface from Metal gpu directly.");
-
-        SkiaDrawing::new(surface)
-    }
-
-    pub fn publish_drawing(&self, drawing: &mut SkiaDrawing) {
-        let mtl_command_buffer = self
-            .metal_context
-            .command_queue
-            .commandBuffer()
-            .expect("Couldn't use command buffer on Metal GPU.");
-        let texture = drawing
-            .get_texture()
-            .expect("Couldn't retrieve internal texture from drawing.");
-        let size = CGSize::new(
-            CGFloat::from(texture.width()),
-            CGFloat::from(texture.height()),
-        );
-        let texture = texture
-            .metal_texture_info()
-            .expect("Couldn't use internal texture from GPU.");
-        let texture = texture.texture();
-        let texture = texture as *const ProtocolObject<dyn MTLTexture>;
-
-        self.syphon_server.publish_frame_texture(
-            texture,
-            Retained::as_ptr(&mtl_command_buffer),
-            NSRect::new(CGPoint { x: 0.0, y: 0.0 }, size),
-            true,
+    /// Reads the drawing back from main memory and publishes it to a frame
+    /// sink. Drawings on the GPU are published as textures instead.
+    #[cfg(not(target_os = "macos"))]
+    pub fn publish_to(&mut self, sink: &mut dyn FrameSink) -> io::Result<()> {
+        let size = self.surface.image_info().dimensions();
+        let info = ImageInfo::new(
+            size,
+            ColorType::RGBA8888,
+            AlphaType::Premul,
+            ColorSpace::new_srgb(),
         );
-        mtl_command_buffer.commit();
-    }
-}
+        let stride = 4 * size.width as usize;
+        let mut data = vec![0; stride * size.height as usize];
+        if !self.surface.read_pixels(&info, &mut data, stride, (0, 0)) {
+            return Err(io::Error::other("couldn't read the pixels of the drawing"));
+        }
 
-impl Drop for SkiaStudio {
-    fn drop(&mut self) {
-        self.syphon_server.stop();
+        sink.publish(&Frame::new(size.width as u32, size.height as u32, &data))
     }
 }
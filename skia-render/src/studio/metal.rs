@@ -0,0 +1,117 @@
+use objc2::{rc::Retained, runtime::ProtocolObject};
+use objc2_core_foundation::{CGFloat, CGPoint, CGSize};
+use objc2_foundation::NSRect;
+use objc2_metal::{MTLCommandBuffer, MTLCommandQueue, MTLDevice, MTLTexture};
+use skia_safe::{
+    gpu::{
+        direct_contexts,
+        mtl::{self, BackendContext},
+        surfaces, Budgeted, SurfaceOrigin,
+    },
+    ColorSpace, ISize, ImageInfo, SurfaceProps,
+};
+use syphon::metal_server::SyphonMetalServer;
+
+use super::SkiaDrawing;
+
+pub struct MetalContext {
+    pub metal_device: Retained<ProtocolObject<dyn MTLDevice>>,
+    pub command_queue: Retained<ProtocolObject<dyn MTLCommandQueue>>,
+    pub sk_metal_backend_context: BackendContext,
+}
+
+impl MetalContext {
+    pub fn new() -> Self {
+        let metal_device = objc2_metal::MTLCreateSystemDefaultDevice()
+            .expect("Failed to get default system device.");
+        let command_queue = metal_device
+            .newCommandQueue()
+            .expect("Failed to create a command queue.");
+        let sk_metal_backend_context = unsafe {
+            mtl::BackendContext::new(
+                Retained::as_ptr(&metal_device) as mtl::Handle,
+                Retained::as_ptr(&command_queue) as mtl::Handle,
+            )
+        };
+
+        Self {
+            metal_device,
+            command_queue,
+            sk_metal_backend_context,
+        }
+    }
+}
+
+pub struct SkiaStudio {
+    metal_context: MetalContext,
+    syphon_server: Retained<SyphonMetalServer>,
+}
+
+impl SkiaStudio {
+    pub fn new() -> Self {
+        let metal_context = MetalContext::new();
+        let syphon_server =
+            SyphonMetalServer::from_device("Open World Game", &metal_context.metal_device);
+
+        Self {
+            metal_context,
+            syphon_server,
+        }
+    }
+
+    pub fn create_drawing(&self, width: i32, height: i32) -> SkiaDrawing {
+        let image_info = ImageInfo::new_n32_premul(ISize { width, height }, ColorSpace::new_srgb());
+        let surface_props = SurfaceProps::default();
+        let mut sk_metal_context =
+            direct_contexts::make_metal(&self.metal_context.sk_metal_backend_context, None)
+                .expect("Failed to create context on Metal gpu directly.");
+
+        let surface = surfaces::render_target(
+            &mut sk_metal_context,
+            Budgeted::No,
+            &image_info,
+            1,
+            SurfaceOrigin::TopLeft,
+            Some(&surface_props),
+            false,
+            false,
+        )
+        .expect("Failed to create surface from Metal gpu directly.");
+
+        SkiaDrawing::new(surface)
+    }
+
+    pub fn publish_drawing(&self, drawing: &mut SkiaDrawing) {
+        let mtl_command_buffer = self
+            .metal_context
+            .command_queue
+            .commandBuffer()
+            .expect("Couldn't use command buffer on Metal GPU.");
+        let texture = drawing
+            .get_texture()
+            .expect("Couldn't retrieve internal texture from drawing.");
+        let size = CGSize::new(
+            CGFloat::from(texture.width()),
+            CGFloat::from(texture.height()),
+        );
+        let texture = texture
+            .metal_texture_info()
+            .expect("Couldn't use internal texture from GPU.");
+        let texture = texture.texture();
+        let texture = texture as *const ProtocolObject<dyn MTLTexture>;
+
+        self.syphon_server.publish_frame_texture(
+            texture,
+            Retained::as_ptr(&mtl_command_buffer),
+            NSRect::new(CGPoint { x: 0.0, y: 0.0 }, size),
+            true,
+        );
+        mtl_command_buffer.commit();
+    }
+}
+
+impl Drop for SkiaStudio {
+    fn drop(&mut self) {
+        self.syphon_server.stop();
+    }
+}
@@ -1,4 +1,11 @@
+use std::env;
+
 fn main() {
+    // Syphilm is only needed for live publishing, which is macOS only.
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("macos") {
+        return;
+    }
+
     println!("cargo::rustc-link-search=framework=/Library/Frameworks",);
     println!("cargo::rustc-link-arg=-Wl,-rpath,/Library/Frameworks");
     println!("cargo::rustc-link-lib=framework=Syphilm");
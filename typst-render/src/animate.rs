@@ -0,0 +1,266 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::Instant;
+
+use color_print::ceprintln;
+use ecow::eco_format;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use tiny_skia::Pixmap;
+use typst::diag::{bail, StrResult};
+use typst::foundations::IntoValue;
+use typst::layout::{Page, PagedDocument};
+
+use crate::args::{ColorSpace, FrameFormat, Output};
+use crate::compile::compile_document;
+use crate::config::SimulationConfig;
+use crate::export::output_template;
+use crate::render::{encode_png, render_page, selected_pages};
+use crate::timings::Timer;
+use crate::world::SystemWorld;
+
+/// The frame rate when none is configured.
+const DEFAULT_FPS: f64 = 30.0;
+
+/// The length of the animation in seconds when none is configured.
+const DEFAULT_DURATION: f64 = 1.0;
+
+/// Execute an animation command.
+///
+/// Frames are compiled one after another in the same world, so that
+/// everything that doesn't depend on the frame is only computed once. The
+/// compiled frames are rasterized in parallel in batches of a few frames per
+/// thread.
+pub fn animate(config: &SimulationConfig) -> StrResult<()> {
+    let Some(output) = &config.output else {
+        bail!("no output was configured for the animation");
+    };
+
+    let args = &config.animation_config;
+    let fps = args.fps.unwrap_or(DEFAULT_FPS);
+    let duration = args.duration.unwrap_or(DEFAULT_DURATION);
+    let count = ((fps * duration).round() as usize).max(1);
+    let format = args.frame_format.unwrap_or_default();
+    let space = config.render_config.color_space.unwrap_or_default();
+    let mut writer = FrameWriter::new(output, format, count, space)?;
+
+    // Create the world that serves sources, files, and fonts.
+    let mut world = SystemWorld::new(&config.input, &config.world_config, &config.process_config)?;
+    let mut timer = Timer::new(&config.process_config);
+
+    let start = Instant::now();
+    let batch = 2 * rayon::current_num_threads();
+    for first in (0..count).step_by(batch) {
+        let frames = first..count.min(first + batch);
+        let pages = frames
+            .clone()
+            .map(|frame| timer.record(&mut world, |world| compile_frame(world, config, frame, fps)))
+            .collect::<StrResult<Vec<_>>>()?;
+
+        let pixmaps = pages
+            .par_iter()
+            .map(|page| render_page(page, config.export_ppi(), &config.render_config))
+            .collect::<StrResult<Vec<_>>>()?;
+
+        for (frame, pixmap) in frames.zip(&pixmaps) {
+            writer.write(frame, pixmap)?;
+        }
+    }
+
+    writer.finish()?;
+    ceprintln!(
+        "<green,bold>rendered</> {} frames in {:.2} s",
+        count,
+        start.elapsed().as_secs_f64()
+    );
+
+    Ok(())
+}
+
+/// Compiles the document for the given frame and returns the page to show.
+fn compile_frame(
+    world: &mut SystemWorld,
+    config: &SimulationConfig,
+    frame: usize,
+    fps: f64,
+) -> StrResult<Page> {
+    let mut world_args = config.world_config.clone();
+    world_args
+        .inputs
+        .push(("frame".into(), (frame as i64).into_value()));
+    world_args
+        .inputs
+        .push(("t".into(), (frame as f64 / fps).into_value()));
+    world.reconfigure(&world_args, &config.process_config);
+    world.reset();
+
+    let document = compile_document::<PagedDocument>(world, config.diagnostic_format())?;
+    let mut pages = selected_pages(&document, config)?;
+    let (_, page) = pages.swap_remove(0);
+    Ok(page.into_owned())
+}
+
+/// Writes the frames of an animation in order.
+enum FrameWriter {
+    /// Writes every frame to its own PNG file.
+    Png {
+        /// The path with a frame number template.
+        template: String,
+        /// The number of frames.
+        count: usize,
+        /// The color space to tag the files with.
+        space: ColorSpace,
+    },
+    /// Writes all frames to one raw stream.
+    Raw {
+        /// The stream.
+        writer: Box<dyn Write>,
+        /// The size of the first frame, which all frames must have.
+        size: Option<(u32, u32)>,
+    },
+}
+
+impl FrameWriter {
+    fn new(
+        output: &Output,
+        format: FrameFormat,
+        count: usize,
+        space: ColorSpace,
+    ) -> StrResult<Self> {
+        Ok(match format {
+            FrameFormat::Png => {
+                let Output::Path(path) = output else {
+                    bail!("cannot write PNG frames to stdout, use the raw frame format instead");
+                };
+                let template = path.to_str().unwrap_or_default().to_owned();
+                if count > 1 && !output_template::has_indexable_template(&template) {
+                    bail!("cannot export multiple frames without a frame number template ({{p}}, {{0p}}) in the output path");
+                }
+                FrameWriter::Png {
+                    template,
+                    count,
+                    space,
+                }
+            }
+            FrameFormat::Raw => {
+                let writer: Box<dyn Write> = match output {
+                    Output::Stdout => Box::new(io::stdout().lock()),
+                    Output::Path(path) => {
+                        Box::new(BufWriter::new(File::create(path).map_err(|err| {
+                            eco_format!("failed to create {} ({err})", path.display())
+                        })?))
+                    }
+                };
+                FrameWriter::Raw { writer, size: None }
+            }
+        })
+    }
+
+    /// Writes the frame with the given zero-based number.
+    fn write(&mut self, frame: usize, pixmap: &Pixmap) -> StrResult<()> {
+        match self {
+            FrameWriter::Png {
+                template,
+                count,
+                space,
+            } => {
+                let path = output_template::format(template, frame + 1, *count);
+                let buf = encode_png(pixmap, *space)?;
+                Output::Path(path.into())
+                    .write(&buf)
+                    .map_err(|err| eco_format!("failed to write PNG file ({err})"))
+            }
+            FrameWriter::Raw { writer, size } => {
+                let frame_size = (pixmap.width(), pixmap.height());
+                match size {
+                    None => {
+                        ceprintln!(
+                            "<cyan,bold>streaming</> {}x{} RGBA frames",
+                            frame_size.0,
+                            frame_size.1
+                        );
+                        *size = Some(frame_size);
+                    }
+                    Some(size) if *size != frame_size => {
+                        bail!(
+                            "frame {} has a different size than the first frame",
+                            frame + 1
+                        );
+                    }
+                    Some(_) => {}
+                }
+
+                let data: Vec<u8> = pixmap
+                    .pixels()
+                    .iter()
+                    .flat_map(|pixel| {
+                        let color = pixel.demultiply();
+                        [color.red(), color.green(), color.blue(), color.alpha()]
+                    })
+                    .collect();
+                writer
+                    .write_all(&data)
+                    .map_err(|err| eco_format!("failed to write frame ({err})"))
+            }
+        }
+    }
+
+    /// Flushes buffered frames.
+    fn finish(&mut self) -> StrResult<()> {
+        match self {
+            FrameWriter::Png { .. } => Ok(()),
+            FrameWriter::Raw { writer, .. } => writer
+                .flush()
+                .map_err(|err| eco_format!("failed to write frame ({err})")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use typst::layout::{Abs, Size};
+
+    use super::*;
+    use crate::args::{FontArgs, Input, WorldArgs};
+
+    #[test]
+    fn sets_frame_and_time_inputs() {
+        let temp = std::env::temp_dir().join(format!("typst-render-frames-{}", std::process::id()));
+        fs::create_dir_all(&temp).unwrap();
+        let main = temp.join("main.typ");
+        fs::write(
+            &main,
+            "#set page(\n\
+               width: (10 + sys.inputs.frame) * 1pt,\n\
+               height: (10 + sys.inputs.t * 10) * 1pt,\n\
+             )",
+        )
+        .unwrap();
+
+        let config = SimulationConfig {
+            input: Input::Path(main),
+            world_config: WorldArgs {
+                root: Some(temp.clone()),
+                font: FontArgs {
+                    ignore_system_fonts: true,
+                    ..FontArgs::default()
+                },
+                ..WorldArgs::default()
+            },
+            ..SimulationConfig::default()
+        };
+        let mut world =
+            SystemWorld::new(&config.input, &config.world_config, &config.process_config).unwrap();
+        let mut size = |frame| {
+            let page = compile_frame(&mut world, &config, frame, 2.0).unwrap();
+            page.frame.size()
+        };
+
+        assert_eq!(size(3), Size::new(Abs::pt(13.0), Abs::pt(25.0)));
+        // The inputs of earlier frames don't stay around.
+        assert_eq!(size(0), Size::new(Abs::pt(10.0), Abs::pt(10.0)));
+
+        fs::remove_dir_all(temp).ok();
+    }
+}
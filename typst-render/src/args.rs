@@ -1,97 +1,802 @@
 use std::fmt::{self, Display, Formatter};
-use std::num::NonZeroUsize;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::num::{NonZeroU32, NonZeroUsize};
 use std::ops::RangeInclusive;
 use std::path::PathBuf;
 use std::str::FromStr;
 
-use chrono::{DateTime, Utc};
-use serde::Deserialize;
+use chrono::{DateTime, FixedOffset, Utc};
+use clap::builder::{TypedValueParser, ValueParser};
+use clap::{Arg, ArgAction, ArgMatches, Args, FromArgMatches, Parser, Subcommand, ValueEnum};
+use ecow::eco_format;
+use serde::{Deserialize, Deserializer};
+use typst::diag::StrResult;
+use typst::foundations::{Dict, IntoValue, Value};
+
+/// The character typically used to separate path components
+/// in environment variables.
+const ENVIRONMENT_PATH_SEPARATOR: char = if cfg!(windows) { ';' } else { ':' };
+
+/// Renders Typst documents into shared textures and files.
+#[derive(Debug, Clone, Parser)]
+#[clap(name = "typst-render", version, about)]
+pub struct CliArguments {
+    /// The command to run. Without one, the document is exported once if an
+    /// output is configured and published live otherwise.
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Path to the config file. Defaults to `config.toml` in the current
+    /// directory if it exists.
+    #[clap(long = "config", global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+}
+
+/// What to do.
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// Compiles an input file into a supported output format.
+    #[command(visible_alias = "c")]
+    Compile(CompileCommand),
+
+    /// Watches an input file and recompiles on changes. Without an output, the
+    /// rendered pages are published live.
+    #[command(visible_alias = "w")]
+    Watch(CompileCommand),
+
+    /// Renders an animation by compiling the input once per frame, with
+    /// `sys.inputs.frame` and `sys.inputs.t` set to the frame number and time.
+    #[command(visible_alias = "a")]
+    Animate(CompileCommand),
+
+    /// Processes an input file to extract provided metadata.
+    Query(QueryCommand),
+
+    /// Copies the packages an input file uses into a project-local directory,
+    /// which is then used as the package path.
+    Vendor(VendorCommand),
+
+    /// Lists all discovered fonts in system and custom font paths.
+    Fonts(FontsCommand),
+}
+
+/// Compiles an input file into a supported output format.
+#[derive(Debug, Clone, Parser)]
+pub struct CompileCommand {
+    /// Arguments for compilation.
+    #[clap(flatten)]
+    pub args: CompileArgs,
+}
+
+/// Processes an input file to extract provided metadata.
+#[derive(Debug, Clone, Parser)]
+pub struct QueryCommand {
+    /// Defines which elements to retrieve, e.g. `<cue>` or `metadata`.
+    pub selector: String,
+
+    /// Path to input Typst file. Use `-` to read input from stdin. Defaults to
+    /// the input of the config file.
+    #[clap(value_parser = input_value_parser())]
+    pub input: Option<Input>,
+
+    /// Extracts just one field from all retrieved elements.
+    #[clap(long = "field")]
+    pub field: Option<String>,
+
+    /// Expects and retrieves exactly one element.
+    #[clap(long = "one")]
+    pub one: bool,
+
+    /// The format to serialize in.
+    #[clap(long = "format", default_value = "json")]
+    pub format: SerializationFormat,
+
+    /// Whether to pretty-print the serialized output.
+    ///
+    /// Only applies to JSON format.
+    #[clap(long)]
+    pub pretty: bool,
+
+    /// World arguments.
+    #[clap(flatten)]
+    pub world: WorldArgs,
+
+    /// Processing arguments.
+    #[clap(flatten)]
+    pub process: ProcessArgs,
+}
+
+/// Copies the packages an input file uses into a project-local directory.
+#[derive(Debug, Clone, Parser)]
+pub struct VendorCommand {
+    /// Path to input Typst file. Use `-` to read input from stdin. Defaults to
+    /// the input of the config file.
+    #[clap(value_parser = input_value_parser())]
+    pub input: Option<Input>,
+
+    /// The directory to copy the packages to. Defaults to `vendor` in the
+    /// project root, which is searched before the package path.
+    #[clap(long = "dir", value_name = "DIR")]
+    pub dir: Option<PathBuf>,
+
+    /// World arguments.
+    #[clap(flatten)]
+    pub world: WorldArgs,
+
+    /// Processing arguments.
+    #[clap(flatten)]
+    pub process: ProcessArgs,
+}
+
+/// Lists all discovered fonts in system and custom font paths.
+#[derive(Debug, Clone, Parser)]
+pub struct FontsCommand {
+    /// Common font arguments.
+    #[clap(flatten)]
+    pub font: FontArgs,
+
+    /// Also lists style variants of each font family.
+    #[arg(long)]
+    pub variants: bool,
+}
 
 /// Arguments for compilation and watching.
-#[derive(Debug, Clone)]
+///
+/// Everything given here overrides the corresponding value of the config file.
+#[derive(Debug, Clone, Default, Args)]
 pub struct CompileArgs {
     /// Path to input Typst file. Use `-` to read input from stdin.
-    pub input: Input,
+    #[clap(value_parser = input_value_parser())]
+    pub input: Option<Input>,
 
-    /// Path to output file
+    /// Path to output file (PDF, PNG or SVG). Use `-` to write output to
+    /// stdout.
+    ///
+    /// For output formats emitting one file per page (PNG & SVG), a page number
+    /// template must be present if the source document renders to multiple
+    /// pages. Use `{p}` for page numbers, `{0p}` for zero padded page numbers
+    /// and `{t}` for page count. For example, `page-{0p}-of-{t}.png` creates
+    /// `page-01-of-10.png`, `page-02-of-10.png`, and so on.
+    #[clap(value_parser = output_value_parser())]
     pub output: Option<Output>,
 
+    /// The format of the output file, inferred from the extension by default.
+    #[arg(long = "format", short = 'f')]
+    pub format: Option<OutputFormat>,
+
     /// World arguments.
+    #[clap(flatten)]
     pub world: WorldArgs,
 
-    /// The PPI (pixels per inch) to use for PNG export.
-    pub ppi: f32,
+    /// Which pages to export or publish. When unspecified, all pages are
+    /// exported.
+    ///
+    /// Pages to export are separated by commas, and can be either simple page
+    /// numbers (e.g. '2,5' to export only pages 2 and 5) or page ranges (e.g.
+    /// '2,3-6,8-' to export page 2, pages 3 to 6 (inclusive), page 8 and any
+    /// pages after it).
+    ///
+    /// Page numbers are one-indexed and correspond to physical page numbers in
+    /// the document (therefore not being affected by the document's page
+    /// counter).
+    #[arg(long = "pages", value_delimiter = ',')]
+    pub pages: Option<Vec<Pages>>,
+
+    /// The PPI (pixels per inch) to render images at. Defaults to 144 for
+    /// exports and animations and to 216 for live outputs.
+    #[arg(long = "ppi")]
+    pub ppi: Option<f32>,
 
     /// File path to which a Makefile with the current compilation's
     /// dependencies will be written.
+    #[clap(long = "make-deps", value_name = "PATH")]
     pub make_deps: Option<PathBuf>,
 
+    /// Arguments for how pages are rasterized.
+    #[clap(flatten)]
+    pub render: RenderArgs,
+
+    /// Arguments for how the rendered pages are published.
+    #[clap(flatten)]
+    pub present: PresentArgs,
+
+    /// Arguments for live `sys.inputs` values.
+    #[clap(flatten)]
+    pub live: LiveArgs,
+
+    /// Arguments for rendering animations.
+    #[clap(flatten)]
+    pub animation: AnimationArgs,
+
+    /// Arguments for packing pages into one image.
+    #[clap(flatten)]
+    pub atlas: AtlasArgs,
+
     /// Processing arguments.
+    #[clap(flatten)]
     pub process: ProcessArgs,
 }
 
+impl CompileArgs {
+    /// Returns the arguments that environment variables like
+    /// `SOURCE_DATE_EPOCH` set, for running without a command.
+    pub fn from_env() -> StrResult<Self> {
+        let command = Self::augment_args(clap::Command::new("typst-render"));
+        command
+            .try_get_matches_from(["typst-render"])
+            .and_then(|matches| Self::from_arg_matches(&matches))
+            .map_err(|err| {
+                let message = err.render().to_string();
+                let message = message.lines().next().unwrap_or_default();
+                message.trim_start_matches("error: ").into()
+            })
+    }
+}
+
 /// Arguments for the construction of a world. Shared by compile, watch, and
 /// query.
-#[derive(Debug, Clone, Default, Deserialize)]
-#[serde(default)]
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct WorldArgs {
     /// Configures the project root (for absolute paths).
+    #[clap(long = "root", env = "TYPST_ROOT", value_name = "DIR")]
     pub root: Option<PathBuf>,
 
-    /// Add a string key-value pair visible through `sys.inputs`.
-    pub inputs: Vec<(String, String)>,
+    /// Key-value pairs visible through `sys.inputs`.
+    ///
+    /// In the config file, this is a table whose values may be of any type,
+    /// e.g. numbers, arrays or nested tables.
+    #[clap(skip)]
+    #[serde(deserialize_with = "deserialize_inputs")]
+    pub inputs: Vec<(String, Value)>,
+
+    /// The inputs given on the command line.
+    #[clap(flatten)]
+    #[serde(skip)]
+    pub cli_inputs: CliInputs,
+
+    /// A JSON file with an object whose entries are visible through
+    /// `sys.inputs`. Inputs given directly take precedence.
+    #[clap(long = "input-file", value_name = "PATH")]
+    pub input_file: Option<PathBuf>,
 
     /// Common font arguments.
+    #[clap(flatten)]
     pub font: FontArgs,
 
     /// Arguments related to storage of packages in the system.
+    #[clap(flatten)]
     pub package: PackageArgs,
+
+    /// The document's creation date formatted as a UNIX timestamp. Pins the
+    /// date returned by `datetime.today()`.
+    ///
+    /// Falls back to the `SOURCE_DATE_EPOCH` environment variable. For more
+    /// information, see <https://reproducible-builds.org/specs/source-date-epoch/>.
+    #[clap(
+        long = "creation-timestamp",
+        env = "SOURCE_DATE_EPOCH",
+        value_name = "UNIX_TIMESTAMP",
+        value_parser = parse_source_date_epoch,
+    )]
+    #[serde(deserialize_with = "deserialize_timestamp")]
+    pub creation_timestamp: Option<DateTime<Utc>>,
+
+    /// The UTC offset used by `datetime.today()` when the document doesn't
+    /// specify one, e.g. `+02:00`. Defaults to the local time zone.
+    #[clap(
+        long = "utc-offset",
+        value_name = "OFFSET",
+        value_parser = parse_utc_offset,
+        allow_hyphen_values = true,
+    )]
+    #[serde(deserialize_with = "deserialize_utc_offset")]
+    pub utc_offset: Option<FixedOffset>,
+}
+
+impl WorldArgs {
+    /// Overrides these arguments with the ones that were given in `other`.
+    /// Inputs and font paths are added to the existing ones.
+    pub fn merge(&mut self, other: WorldArgs) {
+        if other.root.is_some() {
+            self.root = other.root;
+        }
+        self.inputs.extend(other.inputs);
+        self.inputs.extend(other.cli_inputs.0);
+        if other.input_file.is_some() {
+            self.input_file = other.input_file;
+        }
+        self.font.merge(other.font);
+        self.package.merge(other.package);
+        if other.creation_timestamp.is_some() {
+            self.creation_timestamp = other.creation_timestamp;
+        }
+        if other.utc_offset.is_some() {
+            self.utc_offset = other.utc_offset;
+        }
+    }
 }
 
 /// Arguments for configuration the process of compilation itself.
-#[derive(Debug, Clone, Default, Deserialize)]
-#[serde(default)]
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct ProcessArgs {
     /// Number of parallel jobs spawned during compilation. Defaults to number
     /// of CPUs. Setting it to 1 disables parallelism.
+    #[clap(long, short)]
     pub jobs: Option<usize>,
 
     /// Enables in-development features that may be changed or removed at any
     /// time.
+    #[arg(long = "features", value_delimiter = ',', env = "TYPST_FEATURES")]
     pub features: Vec<Feature>,
 
-    /// The format to emit diagnostics in.
-    pub diagnostic_format: DiagnosticFormat,
+    /// The format to emit diagnostics in. Defaults to `human`.
+    #[clap(long)]
+    pub diagnostic_format: Option<DiagnosticFormat>,
+
+    /// Records the timings of the compilation and writes them to the given
+    /// path as a Chrome trace, which Perfetto or `chrome://tracing` can open.
+    #[clap(long = "timings", value_name = "PATH")]
+    pub timings: Option<PathBuf>,
+
+    /// How many compilations to record into the trace while watching.
+    /// Defaults to one.
+    #[clap(long = "timings-compiles", value_name = "N")]
+    pub timings_compiles: Option<NonZeroUsize>,
+}
+
+impl ProcessArgs {
+    /// Overrides these arguments with the ones that were given in `other`.
+    /// Features are added to the existing ones.
+    pub fn merge(&mut self, other: ProcessArgs) {
+        if other.jobs.is_some() {
+            self.jobs = other.jobs;
+        }
+        self.features.extend(other.features);
+        if other.diagnostic_format.is_some() {
+            self.diagnostic_format = other.diagnostic_format;
+        }
+        if other.timings.is_some() {
+            self.timings = other.timings;
+        }
+        if other.timings_compiles.is_some() {
+            self.timings_compiles = other.timings_compiles;
+        }
+    }
+}
+
+/// Arguments for how the rendered pages are exposed to other apps.
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PresentArgs {
+    /// Whether all pages share one output or every page gets its own. Defaults
+    /// to `sequence`.
+    #[clap(long)]
+    pub mode: Option<PageMode>,
+
+    /// Advances a page sequence to the next page after this many seconds.
+    #[clap(long, value_name = "SECONDS")]
+    pub interval: Option<f64>,
+
+    /// Address of a UDP socket that accepts `next`, `previous` and `page <n>`
+    /// messages to control a page sequence.
+    #[clap(long, value_name = "ADDR")]
+    pub control: Option<SocketAddr>,
+
+    /// Where rendered pages are published. Defaults to `syphon` on macOS and
+    /// `png` elsewhere.
+    #[clap(long)]
+    pub sink: Option<SinkKind>,
+
+    /// The directory for `png`, or the shared memory name for
+    /// `shared-memory`. In `separate` mode, the page number is appended.
+    #[clap(long, value_name = "TARGET")]
+    pub sink_target: Option<String>,
+}
+
+impl PresentArgs {
+    /// Overrides these arguments with the ones that were given in `other`.
+    pub fn merge(&mut self, other: PresentArgs) {
+        if other.mode.is_some() {
+            self.mode = other.mode;
+        }
+        if other.interval.is_some() {
+            self.interval = other.interval;
+        }
+        if other.control.is_some() {
+            self.control = other.control;
+        }
+        if other.sink.is_some() {
+            self.sink = other.sink;
+        }
+        if other.sink_target.is_some() {
+            self.sink_target = other.sink_target;
+        }
+    }
+}
+
+/// The `--input` and `--input-json` arguments in the order they were given,
+/// so that a later one overrides an earlier one with the same key.
+#[derive(Debug, Clone, Default)]
+pub struct CliInputs(pub Vec<(String, Value)>);
+
+impl CliInputs {
+    /// The ids of the arguments that add inputs.
+    const IDS: [&'static str; 2] = ["input", "input_json"];
+}
+
+impl Args for CliInputs {
+    fn augment_args(cmd: clap::Command) -> clap::Command {
+        cmd.arg(
+            Arg::new("input")
+                .long("input")
+                .value_name("key=value")
+                .action(ArgAction::Append)
+                .value_parser(ValueParser::new(parse_sys_input_pair))
+                .help("Add a string key-value pair visible through `sys.inputs`"),
+        )
+        .arg(
+            Arg::new("input_json")
+                .long("input-json")
+                .value_name("key=json")
+                .action(ArgAction::Append)
+                .value_parser(ValueParser::new(parse_json_input_pair))
+                .help(
+                    "Add a key-value pair visible through `sys.inputs` whose value is \
+                     parsed as JSON, e.g. `scores=[3, 1]`",
+                ),
+        )
+    }
+
+    fn augment_args_for_update(cmd: clap::Command) -> clap::Command {
+        Self::augment_args(cmd)
+    }
+}
+
+impl FromArgMatches for CliInputs {
+    fn from_arg_matches(matches: &ArgMatches) -> Result<Self, clap::Error> {
+        let mut inputs = vec![];
+        for id in Self::IDS {
+            if let (Some(indices), Some(values)) = (
+                matches.indices_of(id),
+                matches.get_many::<(String, Value)>(id),
+            ) {
+                inputs.extend(indices.zip(values.cloned()));
+            }
+        }
+        inputs.sort_by_key(|(index, _)| *index);
+        Ok(Self(inputs.into_iter().map(|(_, input)| input).collect()))
+    }
+
+    fn update_from_arg_matches(&mut self, matches: &ArgMatches) -> Result<(), clap::Error> {
+        *self = Self::from_arg_matches(matches)?;
+        Ok(())
+    }
+}
+
+/// Arguments for live `sys.inputs` values, which are received while watching
+/// and trigger a recompilation when they change.
+///
+/// Messages are JSON objects like the ones websocket-sync forwards, e.g.
+/// `{"chan1": [0.25, 0.5]}`. Every key becomes an input that keeps its JSON
+/// type, so arrays of samples stay arrays.
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LiveArgs {
+    /// URL of a websocket-sync server to subscribe to, e.g.
+    /// `ws://127.0.0.1:9001`.
+    #[clap(long = "live-websocket", value_name = "URL")]
+    pub websocket: Option<String>,
+
+    /// Address of a UDP socket to receive messages on.
+    #[clap(long = "live-udp", value_name = "ADDR")]
+    pub udp: Option<SocketAddr>,
+
+    /// Only uses the given channels. Defaults to all channels.
+    #[clap(long = "live-channel", value_name = "NAME")]
+    pub channels: Vec<String>,
+}
+
+impl LiveArgs {
+    /// Overrides these arguments with the ones that were given in `other`.
+    pub fn merge(&mut self, other: LiveArgs) {
+        if other.websocket.is_some() {
+            self.websocket = other.websocket;
+        }
+        if other.udp.is_some() {
+            self.udp = other.udp;
+        }
+        if !other.channels.is_empty() {
+            self.channels = other.channels;
+        }
+    }
+}
+
+/// Arguments for rendering animations.
+///
+/// The document is compiled once per frame. `sys.inputs.frame` holds the
+/// zero-based frame number and `sys.inputs.t` the time of the frame in
+/// seconds.
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnimationArgs {
+    /// Frames per second. Defaults to 30.
+    #[clap(long)]
+    pub fps: Option<f64>,
+
+    /// Length of the animation in seconds. Defaults to one second.
+    #[clap(long, value_name = "SECONDS")]
+    pub duration: Option<f64>,
+
+    /// How frames are written. Defaults to `png`.
+    #[clap(long)]
+    pub frame_format: Option<FrameFormat>,
+}
+
+impl AnimationArgs {
+    /// Overrides these arguments with the ones that were given in `other`.
+    pub fn merge(&mut self, other: AnimationArgs) {
+        if other.fps.is_some() {
+            self.fps = other.fps;
+        }
+        if other.duration.is_some() {
+            self.duration = other.duration;
+        }
+        if other.frame_format.is_some() {
+            self.frame_format = other.frame_format;
+        }
+    }
+}
+
+/// Arguments for packing several pages into one image, an atlas.
+///
+/// Pages are either laid out in a grid or placed in explicit rectangles.
+#[derive(Debug, Clone, Default, Hash, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AtlasArgs {
+    /// Packs the selected pages into one image, in a grid with this many
+    /// columns.
+    #[clap(long = "atlas-columns", value_name = "N")]
+    pub columns: Option<NonZeroUsize>,
+
+    /// The space between the cells of the grid in pixels.
+    #[clap(long = "atlas-gap", value_name = "PX")]
+    pub gap: Option<u32>,
+
+    /// The rectangles to place pages in instead of a grid. Each page is fit
+    /// into its rectangle. Only available in the config file.
+    #[clap(skip)]
+    pub rects: Vec<AtlasRect>,
+
+    /// Where to write a JSON file that describes where each page landed.
+    #[clap(long = "atlas-sidecar", value_name = "PATH")]
+    pub sidecar: Option<PathBuf>,
+}
+
+impl AtlasArgs {
+    /// Overrides these arguments with the ones that were given in `other`.
+    pub fn merge(&mut self, other: AtlasArgs) {
+        if other.columns.is_some() {
+            self.columns = other.columns;
+        }
+        if other.gap.is_some() {
+            self.gap = other.gap;
+        }
+        if !other.rects.is_empty() {
+            self.rects = other.rects;
+        }
+        if other.sidecar.is_some() {
+            self.sidecar = other.sidecar;
+        }
+    }
+
+    /// Whether pages are packed into an atlas.
+    pub fn enabled(&self) -> bool {
+        self.columns.is_some() || !self.rects.is_empty()
+    }
+}
+
+/// A rectangle of an atlas that shows a page.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AtlasRect {
+    /// The one-based number of the page to show.
+    pub page: NonZeroUsize,
+    /// The distance from the left edge of the atlas in pixels.
+    pub x: u32,
+    /// The distance from the top edge of the atlas in pixels.
+    pub y: u32,
+    /// The width of the rectangle in pixels.
+    pub width: NonZeroU32,
+    /// The height of the rectangle in pixels.
+    pub height: NonZeroU32,
+}
+
+/// How the frames of an animation are written.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, ValueEnum, Deserialize)]
+pub enum FrameFormat {
+    /// One PNG file per frame. The output path needs a frame number template
+    /// such as `{0p}`.
+    #[default]
+    Png,
+    /// A raw video stream of straight RGBA pixels, e.g. for ffmpeg's
+    /// `rawvideo` demuxer.
+    Raw,
+}
+
+/// Arguments for how pages are rasterized, both for live outputs and for PNG
+/// export.
+#[derive(Debug, Clone, Default, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderArgs {
+    /// Replaces the page background: `transparent`, or a color such as
+    /// `#202020` or `#00000080`. Defaults to the page's own fill.
+    #[clap(long, value_name = "COLOR")]
+    pub background: Option<Background>,
+
+    /// The color space of rendered pixels. Defaults to `srgb`.
+    #[clap(long)]
+    pub color_space: Option<ColorSpace>,
+
+    /// The width of rendered images in pixels. With only one of width and
+    /// height, the other one follows from the page's aspect ratio. Without
+    /// either, the size follows from `ppi`.
+    #[clap(long, value_name = "PX")]
+    pub width: Option<NonZeroU32>,
+
+    /// The height of rendered images in pixels.
+    #[clap(long, value_name = "PX")]
+    pub height: Option<NonZeroU32>,
+
+    /// How pages are fit into the width and height if both are given.
+    /// Defaults to `contain`.
+    #[clap(long)]
+    pub fit: Option<Fit>,
+
+    /// Where pages are placed if they don't fill the image or overflow it.
+    /// Defaults to `center`.
+    #[clap(long)]
+    pub align: Option<Alignment>,
+
+    /// Renders only the element with this label, e.g. `scoreboard` for
+    /// `<scoreboard>`, cropped to its bounding box instead of the pages.
+    #[clap(long, value_name = "LABEL")]
+    pub element: Option<String>,
+
+    /// Space around a cropped element in points. Defaults to zero.
+    #[clap(long, value_name = "PT")]
+    pub padding: Option<f64>,
+}
+
+impl RenderArgs {
+    /// Overrides these arguments with the ones that were given in `other`.
+    pub fn merge(&mut self, other: RenderArgs) {
+        if other.background.is_some() {
+            self.background = other.background;
+        }
+        if other.color_space.is_some() {
+            self.color_space = other.color_space;
+        }
+        if other.width.is_some() {
+            self.width = other.width;
+        }
+        if other.height.is_some() {
+            self.height = other.height;
+        }
+        if other.fit.is_some() {
+            self.fit = other.fit;
+        }
+        if other.align.is_some() {
+            self.align = other.align;
+        }
+        if other.element.is_some() {
+            self.element = other.element;
+        }
+        if other.padding.is_some() {
+            self.padding = other.padding;
+        }
+    }
 }
 
 /// Arguments related to where packages are stored in the system.
-#[derive(Debug, Clone, Default, Deserialize)]
-#[serde(default)]
+#[derive(Debug, Clone, Default, PartialEq, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct PackageArgs {
     /// Custom path to local packages, defaults to system-dependent location.
+    #[clap(long = "package-path", env = "TYPST_PACKAGE_PATH", value_name = "DIR")]
     pub package_path: Option<PathBuf>,
 
     /// Custom path to package cache, defaults to system-dependent location.
+    #[clap(
+        long = "package-cache-path",
+        env = "TYPST_PACKAGE_CACHE_PATH",
+        value_name = "DIR"
+    )]
     pub package_cache_path: Option<PathBuf>,
+
+    /// Never downloads packages. Packages that aren't in the package path or
+    /// cache are reported as missing right away.
+    #[clap(long = "offline", env = "TYPST_OFFLINE")]
+    pub offline: bool,
+
+    /// Path to a custom CA certificate (PEM) to trust when downloading
+    /// packages, e.g. the one of a TLS-intercepting proxy.
+    #[clap(long = "cert", env = "TYPST_CERT", value_name = "PATH")]
+    pub cert: Option<PathBuf>,
+
+    /// The HTTP(S) proxy to download packages through. Without one, the
+    /// `HTTPS_PROXY` and `HTTP_PROXY` environment variables apply.
+    #[clap(long = "proxy", env = "TYPST_PROXY", value_name = "URL")]
+    pub proxy: Option<String>,
+
+    /// Base URL of an alternate package registry, e.g. an internal mirror of
+    /// `https://packages.typst.org`. Packages are fetched from
+    /// `<URL>/<namespace>/<name>-<version>.tar.gz`.
+    #[clap(
+        long = "package-registry",
+        env = "TYPST_PACKAGE_REGISTRY",
+        value_name = "URL"
+    )]
+    pub registry: Option<String>,
+}
+
+impl PackageArgs {
+    /// Overrides these arguments with the ones that were given in `other`.
+    pub fn merge(&mut self, other: PackageArgs) {
+        if other.package_path.is_some() {
+            self.package_path = other.package_path;
+        }
+        if other.package_cache_path.is_some() {
+            self.package_cache_path = other.package_cache_path;
+        }
+        self.offline |= other.offline;
+        if other.cert.is_some() {
+            self.cert = other.cert;
+        }
+        if other.proxy.is_some() {
+            self.proxy = other.proxy;
+        }
+        if other.registry.is_some() {
+            self.registry = other.registry;
+        }
+    }
 }
 
 /// Common arguments to customize available fonts.
-#[derive(Debug, Clone, Default, Deserialize)]
-#[serde(default)]
+#[derive(Debug, Clone, Default, PartialEq, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct FontArgs {
     /// Adds additional directories that are recursively searched for fonts.
     ///
     /// If multiple paths are specified, they are separated by the system's path
     /// separator (`:` on Unix-like systems and `;` on Windows).
+    #[clap(
+        long = "font-path",
+        env = "TYPST_FONT_PATHS",
+        value_name = "DIR",
+        value_delimiter = ENVIRONMENT_PATH_SEPARATOR,
+    )]
     pub font_paths: Vec<PathBuf>,
 
     /// Ensures system fonts won't be searched, unless explicitly included via
     /// `--font-path`.
+    #[arg(long)]
     pub ignore_system_fonts: bool,
 }
 
+impl FontArgs {
+    /// Adds the font paths of `other` and ignores system fonts if either
+    /// ignores them.
+    pub fn merge(&mut self, other: FontArgs) {
+        self.font_paths.extend(other.font_paths);
+        self.ignore_system_fonts |= other.ignore_system_fonts;
+    }
+}
+
 /// An input that is either stdin or a real path.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub enum Input {
     /// Stdin, represented by `-`.
     Stdin,
@@ -99,6 +804,19 @@ pub enum Input {
     Path(PathBuf),
 }
 
+/// The clap value parser used by `CompileArgs.input`
+fn input_value_parser() -> impl TypedValueParser<Value = Input> {
+    clap::builder::OsStringValueParser::new().try_map(|value| {
+        if value.is_empty() {
+            Err(clap::Error::new(clap::error::ErrorKind::InvalidValue))
+        } else if value == "-" {
+            Ok(Input::Stdin)
+        } else {
+            Ok(Input::Path(value.into()))
+        }
+    })
+}
+
 impl Display for Input {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -117,6 +835,32 @@ pub enum Output {
     Path(PathBuf),
 }
 
+/// The clap value parser used by `CompileArgs.output`
+fn output_value_parser() -> impl TypedValueParser<Value = Output> {
+    clap::builder::OsStringValueParser::new().try_map(|value| {
+        // Empty value also handled by clap for `Option<Output>`
+        if value.is_empty() {
+            Err(clap::Error::new(clap::error::ErrorKind::InvalidValue))
+        } else if value == "-" {
+            Ok(Output::Stdout)
+        } else {
+            Ok(Output::Path(value.into()))
+        }
+    })
+}
+
+impl Output {
+    /// Writes the buffer to the file or stdout.
+    pub fn write(&self, buffer: &[u8]) -> StrResult<()> {
+        let result = match self {
+            Output::Path(path) => std::fs::write(path, buffer),
+            Output::Stdout => std::io::stdout().write_all(buffer),
+        };
+
+        result.map_err(|err| eco_format!("{err}"))
+    }
+}
+
 impl Display for Output {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -127,7 +871,7 @@ impl Display for Output {
 }
 
 /// Which format to use for the generated output file.
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, ValueEnum, Deserialize)]
 pub enum OutputFormat {
     Pdf,
     Png,
@@ -136,21 +880,153 @@ pub enum OutputFormat {
 }
 
 /// Which format to use for diagnostics.
-#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Deserialize)]
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, ValueEnum, Deserialize)]
 pub enum DiagnosticFormat {
     #[default]
     Human,
     Short,
 }
 
+/// How multiple rendered pages are exposed.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, ValueEnum, Deserialize)]
+pub enum PageMode {
+    /// One output that shows one page at a time.
+    #[default]
+    Sequence,
+    /// One output per page.
+    Separate,
+}
+
+/// Where rendered pages are published.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum, Deserialize)]
+pub enum SinkKind {
+    /// A Syphon output through Syphilm, only available on macOS.
+    Syphon,
+    /// Numbered PNG files in a directory.
+    Png,
+    /// A ring buffer in POSIX shared memory that other processes can read.
+    SharedMemory,
+}
+
+impl Default for SinkKind {
+    fn default() -> Self {
+        if cfg!(target_os = "macos") {
+            SinkKind::Syphon
+        } else {
+            SinkKind::Png
+        }
+    }
+}
+
+/// Replaces the background of rendered pages.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Background {
+    /// A fully transparent background.
+    Transparent,
+    /// A solid color with straight alpha as RGBA.
+    Color([u8; 4]),
+}
+
+impl<'de> Deserialize<'de> for Background {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl FromStr for Background {
+    type Err = &'static str;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value.eq_ignore_ascii_case("transparent") {
+            return Ok(Background::Transparent);
+        }
+
+        let error = "background must be `transparent` or a color like `#202020` or `#00000080`";
+        let hex = value.strip_prefix('#').ok_or(error)?;
+        if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+            return Err(error);
+        }
+
+        let mut rgba = [0, 0, 0, 255];
+        for (channel, digits) in rgba.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits).map_err(|_| error)?;
+            *channel = u8::from_str_radix(digits, 16).map_err(|_| error)?;
+        }
+        Ok(Background::Color(rgba))
+    }
+}
+
+/// The color space of rendered pixels.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash, ValueEnum, Deserialize)]
+pub enum ColorSpace {
+    /// sRGB, as Typst renders colors.
+    #[default]
+    Srgb,
+    /// Display P3 primaries with the sRGB transfer function.
+    DisplayP3,
+    /// Linear-light sRGB, e.g. for compositors that blend in linear space.
+    Linear,
+}
+
+/// How a page is fit into an image of a given size.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash, ValueEnum, Deserialize)]
+pub enum Fit {
+    /// Scales the page to fit into the image and fills the rest with the
+    /// background.
+    #[default]
+    Contain,
+    /// Scales the page to fill the image and crops what overflows.
+    Cover,
+    /// Scales the page to the image size, distorting it if the aspect ratios
+    /// differ.
+    Stretch,
+}
+
+/// Where a page is placed within an image.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash, ValueEnum, Deserialize)]
+pub enum Alignment {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    #[default]
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Alignment {
+    /// The horizontal and vertical position between zero (left, top) and one
+    /// (right, bottom).
+    pub fn factors(self) -> (f32, f32) {
+        let x = match self {
+            Self::TopLeft | Self::Left | Self::BottomLeft => 0.0,
+            Self::Top | Self::Center | Self::Bottom => 0.5,
+            Self::TopRight | Self::Right | Self::BottomRight => 1.0,
+        };
+        let y = match self {
+            Self::TopLeft | Self::Top | Self::TopRight => 0.0,
+            Self::Left | Self::Center | Self::Right => 0.5,
+            Self::BottomLeft | Self::Bottom | Self::BottomRight => 1.0,
+        };
+        (x, y)
+    }
+}
+
 /// An in-development feature that may be changed or removed at any time.
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum, Deserialize)]
 pub enum Feature {
     Html,
 }
 
-// Output file format for query command
-#[derive(Debug, Default, Copy, Clone, Eq, PartialEq)]
+/// Output file format for query command.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, ValueEnum)]
 pub enum SerializationFormat {
     #[default]
     Json,
@@ -165,6 +1041,24 @@ pub enum SerializationFormat {
 #[derive(Debug, Clone)]
 pub struct Pages(pub RangeInclusive<Option<NonZeroUsize>>);
 
+impl Pages {
+    /// Whether the range contains the given page number.
+    pub fn includes(&self, number: NonZeroUsize) -> bool {
+        self.0.start().is_none_or(|start| start <= number)
+            && self.0.end().is_none_or(|end| number <= end)
+    }
+}
+
+impl<'de> Deserialize<'de> for Pages {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 impl FromStr for Pages {
     type Err = &'static str;
 
@@ -210,7 +1104,21 @@ fn parse_page_number(value: &str) -> Result<NonZeroUsize, &'static str> {
 ///
 /// This function will return an error if the argument contains no equals sign
 /// or contains the key (before the equals sign) is empty.
-fn parse_sys_input_pair(raw: &str) -> Result<(String, String), String> {
+fn parse_sys_input_pair(raw: &str) -> Result<(String, Value), String> {
+    let (key, val) = split_input_pair(raw)?;
+    Ok((key, val.into_value()))
+}
+
+/// Parses key/value pairs split by the first equal sign, where the value is
+/// JSON.
+fn parse_json_input_pair(raw: &str) -> Result<(String, Value), String> {
+    let (key, val) = split_input_pair(raw)?;
+    let val = serde_json::from_str(val).map_err(|err| format!("invalid JSON value ({err})"))?;
+    Ok((key, val))
+}
+
+/// Splits a key/value pair at the first equal sign and trims both parts.
+fn split_input_pair(raw: &str) -> Result<(String, &str), String> {
     let (key, val) = raw
         .split_once('=')
         .ok_or("input must be a key and a value separated by an equal sign")?;
@@ -218,8 +1126,7 @@ fn parse_sys_input_pair(raw: &str) -> Result<(String, String), String> {
     if key.is_empty() {
         return Err("the key was missing or empty".to_owned());
     }
-    let val = val.trim().to_owned();
-    Ok((key, val))
+    Ok((key, val.trim()))
 }
 
 /// Parses a UNIX timestamp according to <https://reproducible-builds.org/specs/source-date-epoch/>
@@ -229,3 +1136,206 @@ fn parse_source_date_epoch(raw: &str) -> Result<DateTime<Utc>, String> {
         .map_err(|err| format!("timestamp must be decimal integer ({err})"))?;
     DateTime::from_timestamp(timestamp, 0).ok_or_else(|| "timestamp out of range".to_string())
 }
+
+/// Parses a UTC offset such as `+02:00`, `-0530`, `+9` or `Z`.
+pub fn parse_utc_offset(raw: &str) -> Result<FixedOffset, String> {
+    let raw = raw.trim();
+    if matches!(raw, "Z" | "z" | "UTC") {
+        return Ok(FixedOffset::east_opt(0).unwrap());
+    }
+
+    let (sign, rest) = match raw.split_at_checked(1) {
+        Some(("+", rest)) => (1, rest),
+        Some(("-", rest)) => (-1, rest),
+        _ => return Err("offset must start with `+` or `-`".into()),
+    };
+
+    let (hours, minutes) = match rest.split_once(':') {
+        Some((hours, minutes)) => (hours, minutes),
+        None if rest.len() == 4 && rest.is_ascii() => rest.split_at(2),
+        None => (rest, "0"),
+    };
+
+    // Only digits may follow the sign, so that e.g. `+-5` is rejected.
+    let number = |digits: &str, unit: &str| -> Result<i32, String> {
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(format!("{unit} must be a number"));
+        }
+        digits
+            .parse()
+            .map_err(|_| "offset must be less than 24 hours".into())
+    };
+    let hours = number(hours, "hours")?;
+    let minutes = number(minutes, "minutes")?;
+    if hours > 23 || minutes > 59 {
+        return Err("offset must be less than 24 hours".into());
+    }
+
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+        .ok_or_else(|| "offset out of range".into())
+}
+
+/// Deserializes inputs from a table, or from a list of key-value pairs as
+/// older config files have them.
+fn deserialize_inputs<'de, D>(deserializer: D) -> Result<Vec<(String, Value)>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Inputs {
+        Table(Dict),
+        Pairs(Vec<(String, Value)>),
+    }
+
+    Ok(match Inputs::deserialize(deserializer)? {
+        Inputs::Table(dict) => dict
+            .into_iter()
+            .map(|(key, value)| (key.as_str().into(), value))
+            .collect(),
+        Inputs::Pairs(pairs) => pairs,
+    })
+}
+
+/// Deserializes an optional UNIX timestamp.
+fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let timestamp = i64::deserialize(deserializer)?;
+    DateTime::from_timestamp(timestamp, 0)
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom("timestamp out of range"))
+}
+
+/// Deserializes an optional UTC offset.
+fn deserialize_utc_offset<'de, D>(deserializer: D) -> Result<Option<FixedOffset>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_utc_offset(&s)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use typst::foundations::{array, dict};
+
+    use super::*;
+
+    #[test]
+    fn parses_page_ranges() {
+        let range = |raw: &str| raw.parse::<Pages>().map(|pages| pages.0);
+        assert_eq!(range("1-3"), Ok(page(1)..=page(3)));
+        assert_eq!(range("4"), Ok(page(4)..=page(4)));
+        assert_eq!(range("5-"), Ok(page(5)..=None));
+        assert_eq!(range("-2"), Ok(None..=page(2)));
+        for raw in ["", "-", "0", "3-1", "1-2-3", "x"] {
+            assert!(range(raw).is_err(), "{raw:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn parses_page_lists() {
+        let command = CompileArgs::augment_args(clap::Command::new("typst-render"));
+        let matches = command
+            .try_get_matches_from(["typst-render", "--pages", "1-3,5"])
+            .unwrap();
+        let pages = CompileArgs::from_arg_matches(&matches)
+            .unwrap()
+            .pages
+            .unwrap();
+        let ranges: Vec<_> = pages.into_iter().map(|pages| pages.0).collect();
+        assert_eq!(ranges, [page(1)..=page(3), page(5)..=page(5)]);
+    }
+
+    #[test]
+    fn parses_typed_json_inputs() {
+        let (key, value) =
+            parse_json_input_pair(r#"scores = [1, 2.5, true, {"a": null}]"#).unwrap();
+        assert_eq!(key, "scores");
+        assert_eq!(
+            value,
+            array![1, 2.5, true, dict! { "a" => Value::None }].into_value()
+        );
+        assert!(parse_json_input_pair("scores=[1,").is_err());
+
+        // Plain inputs stay strings.
+        assert_eq!(parse_sys_input_pair("n=3").unwrap().1, "3".into_value());
+    }
+
+    #[test]
+    fn parses_typed_toml_inputs() {
+        let world: WorldArgs =
+            toml::from_str("inputs = { n = 3, f = 1.5, list = [1, 2], nested = { on = true } }")
+                .unwrap();
+        let inputs: Dict = world
+            .inputs
+            .into_iter()
+            .map(|(k, v)| (k.into(), v))
+            .collect();
+        assert_eq!(
+            inputs,
+            dict! {
+                "n" => 3,
+                "f" => 1.5,
+                "list" => array![1, 2],
+                "nested" => dict! { "on" => true },
+            }
+        );
+
+        // Older config files list key-value pairs.
+        let world: WorldArgs = toml::from_str(r#"inputs = [["mode", "dark"]]"#).unwrap();
+        assert_eq!(world.inputs, [("mode".into(), "dark".into_value())]);
+    }
+
+    #[test]
+    fn parses_utc_offsets() {
+        let offset = |raw| parse_utc_offset(raw).map(|offset| offset.local_minus_utc());
+        assert_eq!(offset("Z"), Ok(0));
+        assert_eq!(offset("UTC"), Ok(0));
+        assert_eq!(offset("+05:30"), Ok(5 * 3600 + 30 * 60));
+        assert_eq!(offset("-0530"), Ok(-(5 * 3600 + 30 * 60)));
+        assert_eq!(offset("-8"), Ok(-8 * 3600));
+    }
+
+    #[test]
+    fn rejects_invalid_utc_offsets() {
+        let invalid = [
+            "+24:00", "-05:60", "+-5", "++5", "-+05:00", "+5:+3", "5", "+", "+05:",
+        ];
+        for raw in invalid {
+            assert!(parse_utc_offset(raw).is_err(), "{raw} was accepted");
+        }
+    }
+
+    #[test]
+    fn reads_creation_timestamp_from_environment() {
+        let command = WorldArgs::augment_args(clap::Command::new("typst-render"));
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_id() == "creation_timestamp")
+            .unwrap();
+        assert_eq!(arg.get_env(), Some(OsStr::new("SOURCE_DATE_EPOCH")));
+    }
+
+    #[test]
+    fn command_line_overrides_config_timestamp() {
+        let mut world: WorldArgs = toml::from_str("creation_timestamp = 1").unwrap();
+        let command = WorldArgs::augment_args(clap::Command::new("typst-render"));
+        let matches = command
+            .try_get_matches_from(["typst-render", "--creation-timestamp", "2"])
+            .unwrap();
+        world.merge(WorldArgs::from_arg_matches(&matches).unwrap());
+        assert_eq!(world.creation_timestamp, DateTime::from_timestamp(2, 0));
+    }
+
+    /// A page number.
+    fn page(number: usize) -> Option<NonZeroUsize> {
+        NonZeroUsize::new(number)
+    }
+}
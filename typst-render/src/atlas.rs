@@ -0,0 +1,362 @@
+use std::borrow::Cow;
+use std::fs;
+use std::path::Path;
+
+use ecow::eco_format;
+use serde::Serialize;
+use taffy::prelude::{auto, length, AlignItems, Display, Size, Style, TaffyMaxContent, TaffyTree};
+use tiny_skia::{BlendMode, Pixmap, PixmapPaint, Transform};
+use typst::diag::{bail, StrResult};
+use typst::layout::{Page, PagedDocument};
+
+use crate::args::{AtlasArgs, AtlasRect, Background, RenderArgs};
+use crate::config::SimulationConfig;
+use crate::render::{new_pixmap, selected_pages, RenderedPage};
+
+/// Where the pages of an atlas landed. Written as the JSON sidecar.
+#[derive(Debug, Clone, Serialize)]
+pub struct AtlasLayout {
+    /// The width of the atlas in pixels.
+    pub width: u32,
+    /// The height of the atlas in pixels.
+    pub height: u32,
+    /// The placed pages in the order they were configured.
+    pub pages: Vec<Placement>,
+}
+
+/// Where a page landed in an atlas, in pixels from the top-left corner.
+#[derive(Debug, Clone, Serialize)]
+pub struct Placement {
+    /// The one-based page number.
+    pub page: usize,
+    /// The distance of the page from the left edge.
+    pub x: u32,
+    /// The distance of the page from the top edge.
+    pub y: u32,
+    /// The width of the rendered page.
+    pub width: u32,
+    /// The height of the rendered page.
+    pub height: u32,
+}
+
+/// Returns the pages that go into the atlas with their zero-based index and
+/// the arguments to render each of them with.
+///
+/// In a grid, these are the selected pages. With explicit rectangles, they
+/// are the pages the rectangles name, sized to fit their rectangle.
+pub fn atlas_pages<'a>(
+    document: &'a PagedDocument,
+    config: &'a SimulationConfig,
+) -> StrResult<Vec<(usize, Cow<'a, Page>, Cow<'a, RenderArgs>)>> {
+    let rects = &config.atlas_config.rects;
+    if rects.is_empty() {
+        return Ok(selected_pages(document, config)?
+            .into_iter()
+            .map(|(i, page)| (i, page, Cow::Borrowed(&config.render_config)))
+            .collect());
+    }
+
+    rects
+        .iter()
+        .map(|rect| {
+            let index = rect.page.get() - 1;
+            let Some(page) = document.pages.get(index) else {
+                bail!(
+                    "page {} of the atlas doesn't exist in the document",
+                    rect.page
+                );
+            };
+            let args = RenderArgs {
+                width: Some(rect.width),
+                height: Some(rect.height),
+                ..config.render_config.clone()
+            };
+            Ok((index, Cow::Borrowed(page), Cow::Owned(args)))
+        })
+        .collect()
+}
+
+/// Checks that the configured rectangles fit into an image and don't
+/// overlap.
+pub fn check_rects(rects: &[AtlasRect]) -> Result<(), String> {
+    let bounds = |rect: &AtlasRect| {
+        let right = rect.x.checked_add(rect.width.get());
+        let bottom = rect.y.checked_add(rect.height.get());
+        right.zip(bottom).ok_or_else(|| {
+            format!(
+                "the rectangle of page {} lies outside of any image",
+                rect.page
+            )
+        })
+    };
+
+    for (i, a) in rects.iter().enumerate() {
+        let (a_right, a_bottom) = bounds(a)?;
+        for b in &rects[..i] {
+            let (b_right, b_bottom) = bounds(b)?;
+            if a.x < b_right && b.x < a_right && a.y < b_bottom && b.y < a_bottom {
+                return Err(format!(
+                    "the rectangles of pages {} and {} overlap",
+                    b.page, a.page
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Lays out rendered pages in the configured rectangles, or in a grid whose
+/// columns and rows are as large as their largest page.
+pub fn layout(
+    args: &AtlasArgs,
+    numbers: &[usize],
+    pages: &[RenderedPage],
+) -> StrResult<AtlasLayout> {
+    let placements: Vec<_> = if args.rects.is_empty() {
+        layout_grid(args, pages)?
+    } else {
+        args.rects.iter().map(|rect| (rect.x, rect.y)).collect()
+    };
+
+    let pages: Vec<_> = numbers
+        .iter()
+        .zip(pages)
+        .zip(placements)
+        .map(|((&page, rendered), (x, y))| Placement {
+            page,
+            x,
+            y,
+            width: rendered.pixmap.width(),
+            height: rendered.pixmap.height(),
+        })
+        .collect();
+
+    let (mut width, mut height) = (1, 1);
+    for placement in &pages {
+        let right = placement.x.checked_add(placement.width);
+        let bottom = placement.y.checked_add(placement.height);
+        let Some((right, bottom)) = right.zip(bottom) else {
+            bail!("page {} lies outside of the atlas", placement.page);
+        };
+        width = width.max(right);
+        height = height.max(bottom);
+    }
+
+    Ok(AtlasLayout {
+        width,
+        height,
+        pages,
+    })
+}
+
+/// Positions the pages in a grid with taffy.
+fn layout_grid(args: &AtlasArgs, pages: &[RenderedPage]) -> StrResult<Vec<(u32, u32)>> {
+    let error = |err| eco_format!("failed to lay out atlas ({err})");
+    let columns = args.columns.map_or(1, |columns| columns.get());
+    let gap = args.gap.unwrap_or_default() as f32;
+
+    let mut tree: TaffyTree<()> = TaffyTree::new();
+    let cells = pages
+        .iter()
+        .map(|page| {
+            tree.new_leaf(Style {
+                size: Size {
+                    width: length(page.pixmap.width() as f32),
+                    height: length(page.pixmap.height() as f32),
+                },
+                ..Style::default()
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(error)?;
+
+    let grid = Style {
+        display: Display::Grid,
+        grid_template_columns: vec![auto(); columns],
+        gap: Size {
+            width: length(gap),
+            height: length(gap),
+        },
+        align_items: Some(AlignItems::Start),
+        justify_items: Some(AlignItems::Start),
+        ..Style::default()
+    };
+    let root = tree.new_with_children(grid, &cells).map_err(error)?;
+    tree.compute_layout(root, Size::MAX_CONTENT)
+        .map_err(error)?;
+
+    cells
+        .into_iter()
+        .map(|cell| {
+            let location = tree.layout(cell).map_err(error)?.location;
+            Ok((location.x.round() as u32, location.y.round() as u32))
+        })
+        .collect()
+}
+
+/// Draws the rendered pages into one image according to the layout. Space
+/// that no page covers is filled with the configured background color, or
+/// left transparent.
+///
+/// The pages already have the background, so they replace the canvas where
+/// they're placed instead of being blended with it a second time.
+pub fn compose(
+    layout: &AtlasLayout,
+    pages: &[RenderedPage],
+    background: Option<Background>,
+) -> StrResult<Pixmap> {
+    let mut canvas = new_pixmap(layout.width, layout.height)?;
+    if let Some(Background::Color([r, g, b, a])) = background {
+        canvas.fill(tiny_skia::Color::from_rgba8(r, g, b, a));
+    }
+
+    let paint = PixmapPaint {
+        blend_mode: BlendMode::Source,
+        ..PixmapPaint::default()
+    };
+    for (placement, page) in layout.pages.iter().zip(pages) {
+        canvas.draw_pixmap(
+            placement.x as i32,
+            placement.y as i32,
+            Pixmap::as_ref(&page.pixmap),
+            &paint,
+            Transform::identity(),
+            None,
+        );
+    }
+
+    Ok(canvas)
+}
+
+/// Writes the layout of an atlas as JSON.
+pub fn write_sidecar(path: &Path, layout: &AtlasLayout) -> StrResult<()> {
+    let json = serde_json::to_vec_pretty(layout)
+        .map_err(|err| eco_format!("failed to serialize atlas layout ({err})"))?;
+    fs::write(path, json)
+        .map_err(|err| eco_format!("failed to write atlas layout to {} ({err})", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::{NonZeroU32, NonZeroUsize};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn lays_out_grid_with_gaps() {
+        let args = AtlasArgs {
+            columns: NonZeroUsize::new(2),
+            gap: Some(4),
+            ..AtlasArgs::default()
+        };
+        let pages = [page(10, 20), page(30, 10), page(5, 5)];
+
+        // Columns are as wide and rows as tall as their largest page.
+        let layout = layout(&args, &[1, 2, 4], &pages).unwrap();
+        assert_eq!(positions(&layout), [(1, 0, 0), (2, 14, 0), (4, 0, 24)]);
+        assert_eq!((layout.width, layout.height), (44, 29));
+    }
+
+    #[test]
+    fn lays_out_rects() {
+        let args = AtlasArgs {
+            rects: vec![rect(1, 0, 0, 20, 10), rect(3, 0, 10, 8, 8)],
+            ..AtlasArgs::default()
+        };
+        let pages = [page(20, 10), page(8, 8)];
+
+        let layout = layout(&args, &[1, 3], &pages).unwrap();
+        assert_eq!(positions(&layout), [(1, 0, 0), (3, 0, 10)]);
+        assert_eq!((layout.width, layout.height), (20, 18));
+    }
+
+    #[test]
+    fn rejects_overlapping_and_overflowing_rects() {
+        assert!(check_rects(&[rect(1, 0, 0, 10, 10), rect(2, 10, 0, 10, 10)]).is_ok());
+
+        let err = check_rects(&[rect(1, 0, 0, 10, 10), rect(2, 9, 9, 10, 10)]).unwrap_err();
+        assert!(err.contains("pages 1 and 2 overlap"), "{err}");
+
+        let err = check_rects(&[rect(1, u32::MAX, 0, 1, 1)]).unwrap_err();
+        assert!(err.contains("page 1"), "{err}");
+    }
+
+    #[test]
+    fn writes_sidecar() {
+        let args = AtlasArgs {
+            rects: vec![rect(2, 5, 0, 20, 10)],
+            ..AtlasArgs::default()
+        };
+        let layout = layout(&args, &[2], &[page(20, 10)]).unwrap();
+
+        let path =
+            std::env::temp_dir().join(format!("typst-render-atlas-{}.json", std::process::id()));
+        write_sidecar(&path, &layout).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "width": 25,
+                "height": 10,
+                "pages": [{ "page": 2, "x": 5, "y": 0, "width": 20, "height": 10 }],
+            })
+        );
+
+        fs::remove_file(path).ok();
+    }
+
+    /// A blank rendered page of the given size.
+    fn page(width: u32, height: u32) -> RenderedPage {
+        RenderedPage {
+            hash: 0,
+            pixmap: Arc::new(Pixmap::new(width, height).unwrap()),
+        }
+    }
+
+    /// A rectangle that shows the given page.
+    fn rect(page: usize, x: u32, y: u32, width: u32, height: u32) -> AtlasRect {
+        AtlasRect {
+            page: NonZeroUsize::new(page).unwrap(),
+            x,
+            y,
+            width: NonZeroU32::new(width).unwrap(),
+            height: NonZeroU32::new(height).unwrap(),
+        }
+    }
+
+    /// The page numbers and positions of a layout.
+    fn positions(layout: &AtlasLayout) -> Vec<(usize, u32, u32)> {
+        layout.pages.iter().map(|p| (p.page, p.x, p.y)).collect()
+    }
+
+    #[test]
+    fn composes_translucent_background_once() {
+        // Pages come out of rendering with the background already applied.
+        let background = Background::Color([0, 0, 0, 128]);
+        let mut pixmap = Pixmap::new(2, 2).unwrap();
+        pixmap.fill(tiny_skia::Color::from_rgba8(0, 0, 0, 128));
+        let page = RenderedPage {
+            hash: 0,
+            pixmap: Arc::new(pixmap),
+        };
+        let layout = AtlasLayout {
+            width: 4,
+            height: 2,
+            pages: vec![Placement {
+                page: 1,
+                x: 0,
+                y: 0,
+                width: 2,
+                height: 2,
+            }],
+        };
+
+        let atlas = compose(&layout, &[page], Some(background)).unwrap();
+        let inside = atlas.pixel(0, 0).unwrap();
+        let outside = atlas.pixel(3, 0).unwrap();
+        assert_eq!(inside.alpha(), 128);
+        assert_eq!(inside, outside);
+    }
+}
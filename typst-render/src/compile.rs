@@ -0,0 +1,281 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use codespan_reporting::diagnostic::{Diagnostic, Label};
+use codespan_reporting::term;
+use codespan_reporting::term::termcolor::{ColorChoice, StandardStream, WriteColor};
+use ecow::{eco_format, EcoVec};
+use typst::diag::{bail, Severity, SourceDiagnostic, StrResult, Warned};
+use typst::syntax::{FileId, Span};
+use typst::{Document, WorldExt};
+
+use crate::args::{DiagnosticFormat, Output};
+use crate::config::SimulationConfig;
+use crate::export::export_once;
+use crate::timings::Timer;
+use crate::world::SystemWorld;
+
+/// Execute a compilation command.
+pub fn compile(config: &SimulationConfig) -> StrResult<()> {
+    // Create the world that serves sources, files, and fonts.
+    let mut world = SystemWorld::new(&config.input, &config.world_config, &config.process_config)?;
+
+    let mut timer = Timer::new(&config.process_config);
+    timer.record(&mut world, |world| export_once(world, config))
+}
+
+/// Compiles the world's main file and prints all warnings and errors.
+///
+/// A failed compilation is reported as an error after its diagnostics have
+/// been printed, so callers can keep whatever they produced last.
+pub fn compile_document<D: Document>(
+    world: &SystemWorld,
+    diagnostic_format: DiagnosticFormat,
+) -> StrResult<D> {
+    let Warned { output, warnings } = typst::compile::<D>(world);
+
+    let (document, errors) = match output {
+        Ok(document) => (Some(document), EcoVec::new()),
+        Err(errors) => (None, errors),
+    };
+
+    print_diagnostics(world, &errors, &warnings, diagnostic_format)
+        .map_err(|err| eco_format!("failed to print diagnostics ({err})"))?;
+
+    document.ok_or_else(|| eco_format!("compilation failed with {} error(s)", errors.len()))
+}
+
+/// Writes a Makefile rule describing the relationship between the output and
+/// its dependencies to the configured file, if any.
+pub fn write_make_deps(world: &mut SystemWorld, config: &SimulationConfig) -> StrResult<()> {
+    let Some(ref make_deps_path) = config.make_deps else {
+        return Ok(());
+    };
+    let Some(Output::Path(output_path)) = &config.output else {
+        bail!("failed to create make dependencies file because no output path was configured")
+    };
+    let Some(output_path) = output_path.as_os_str().to_str() else {
+        bail!("failed to create make dependencies file because output path was not valid unicode")
+    };
+
+    let root = world.root().to_owned();
+    write_deps(make_deps_path, output_path, &root, world.dependencies()).map_err(|err| {
+        eco_format!("failed to create make dependencies file due to IO error ({err})")
+    })
+}
+
+/// Escapes a path for a Makefile rule.
+///
+/// Based on `munge` in libcpp/mkdeps.cc from the GCC source code. This isn't
+/// perfect as some special characters can't be escaped.
+fn munge(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    let mut slashes = 0;
+    for c in s.chars() {
+        match c {
+            '\\' => slashes += 1,
+            '$' => {
+                res.push('$');
+                slashes = 0;
+            }
+            ':' => {
+                res.push('\\');
+                slashes = 0;
+            }
+            ' ' | '\t' => {
+                // `munge`'s source contains a comment here that says: "A
+                // space or tab preceded by 2N+1 backslashes represents N
+                // backslashes followed by space..."
+                for _ in 0..slashes + 1 {
+                    res.push('\\');
+                }
+                slashes = 0;
+            }
+            '#' => {
+                res.push('\\');
+                slashes = 0;
+            }
+            _ => slashes = 0,
+        };
+        res.push(c);
+    }
+    res
+}
+
+/// Writes a rule that makes the output depend on the dependencies, with paths
+/// inside the root relative to it.
+fn write_deps(
+    make_deps_path: &Path,
+    output_path: &str,
+    root: &Path,
+    dependencies: impl Iterator<Item = PathBuf>,
+) -> io::Result<()> {
+    let mut file = File::create(make_deps_path)?;
+
+    file.write_all(munge(output_path).as_bytes())?;
+    file.write_all(b":")?;
+    for dependency in dependencies {
+        let Some(dependency) = dependency
+            .strip_prefix(root)
+            .unwrap_or(&dependency)
+            .to_str()
+        else {
+            // Silently skip paths that aren't valid unicode so we still
+            // produce a rule that will work for the other paths that can be
+            // processed.
+            continue;
+        };
+
+        file.write_all(b" ")?;
+        file.write_all(munge(dependency).as_bytes())?;
+    }
+    file.write_all(b"\n")?;
+
+    Ok(())
+}
+
+/// Print diagnostic messages to the terminal.
+pub fn print_diagnostics(
+    world: &SystemWorld,
+    errors: &[SourceDiagnostic],
+    warnings: &[SourceDiagnostic],
+    diagnostic_format: DiagnosticFormat,
+) -> Result<(), codespan_reporting::files::Error> {
+    let mut w = match diagnostic_format {
+        DiagnosticFormat::Human => StandardStream::stderr(ColorChoice::Auto),
+        DiagnosticFormat::Short => StandardStream::stderr(ColorChoice::Never),
+    };
+    write_diagnostics(&mut w, world, errors, warnings, diagnostic_format)
+}
+
+/// Writes diagnostic messages in the given format.
+fn write_diagnostics(
+    w: &mut dyn WriteColor,
+    world: &SystemWorld,
+    errors: &[SourceDiagnostic],
+    warnings: &[SourceDiagnostic],
+    diagnostic_format: DiagnosticFormat,
+) -> Result<(), codespan_reporting::files::Error> {
+    let mut config = term::Config {
+        tab_width: 2,
+        ..Default::default()
+    };
+    if diagnostic_format == DiagnosticFormat::Short {
+        config.display_style = term::DisplayStyle::Short;
+    }
+
+    for diagnostic in warnings.iter().chain(errors) {
+        let diag = match diagnostic.severity {
+            Severity::Error => Diagnostic::error(),
+            Severity::Warning => Diagnostic::warning(),
+        }
+        .with_message(diagnostic.message.clone())
+        .with_notes(
+            diagnostic
+                .hints
+                .iter()
+                .map(|e| (eco_format!("hint: {e}")).into())
+                .collect(),
+        )
+        .with_labels(label(world, diagnostic.span).into_iter().collect());
+
+        term::emit(w, &config, world, &diag)?;
+
+        // Stacktrace-like helper diagnostics.
+        for point in &diagnostic.trace {
+            let message = point.v.to_string();
+            let help = Diagnostic::help()
+                .with_message(message)
+                .with_labels(label(world, point.span).into_iter().collect());
+
+            term::emit(w, &config, world, &help)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Create a label for a span.
+fn label(world: &SystemWorld, span: Span) -> Option<Label<FileId>> {
+    Some(Label::primary(span.id()?, world.range(span)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use codespan_reporting::term::termcolor::NoColor;
+    use typst::layout::PagedDocument;
+
+    use super::*;
+    use crate::args::{FontArgs, Input, ProcessArgs, WorldArgs};
+
+    #[test]
+    fn writes_short_and_human_diagnostics() {
+        let temp = std::env::temp_dir().join(format!("typst-render-diag-{}", std::process::id()));
+        fs::create_dir_all(&temp).unwrap();
+        let main = temp.join("main.typ");
+        fs::write(&main, "= Title\n#nope\n").unwrap();
+
+        let world_args = WorldArgs {
+            root: Some(temp.clone()),
+            font: FontArgs {
+                ignore_system_fonts: true,
+                ..FontArgs::default()
+            },
+            ..WorldArgs::default()
+        };
+        let world =
+            SystemWorld::new(&Input::Path(main), &world_args, &ProcessArgs::default()).unwrap();
+        let errors = typst::compile::<PagedDocument>(&world).output.unwrap_err();
+        let write = |format| {
+            let mut w = NoColor::new(Vec::new());
+            write_diagnostics(&mut w, &world, &errors, &[], format).unwrap();
+            String::from_utf8(w.into_inner()).unwrap()
+        };
+
+        let short = write(DiagnosticFormat::Short);
+        assert_eq!(short.lines().count(), 1, "{short}");
+        assert!(short
+            .trim_end()
+            .ends_with("main.typ:2:2: error: unknown variable: nope"));
+
+        let human = write(DiagnosticFormat::Human);
+        assert!(
+            human.starts_with("error: unknown variable: nope"),
+            "{human}"
+        );
+        assert!(human.contains("main.typ:2:2"));
+        assert!(human.contains("#nope"));
+
+        fs::remove_dir_all(temp).ok();
+    }
+
+    #[test]
+    fn escapes_special_characters() {
+        assert_eq!(munge("out/page 1.png"), r"out/page\ 1.png");
+        assert_eq!(munge("tab\there"), "tab\\\there");
+        assert_eq!(munge("$price.typ"), "$$price.typ");
+        assert_eq!(munge("#1.typ"), r"\#1.typ");
+        assert_eq!(munge("C:/doc.typ"), r"C\:/doc.typ");
+        // A backslash before a space is doubled so that it stays a backslash.
+        assert_eq!(munge(r"a\ b"), r"a\\\ b");
+    }
+
+    #[test]
+    fn writes_rule_with_relative_dependencies() {
+        let temp = std::env::temp_dir().join(format!("typst-render-deps-{}", std::process::id()));
+        fs::create_dir_all(&temp).unwrap();
+        let deps = temp.join("out.d");
+        let dependencies = [temp.join("main file.typ"), PathBuf::from("/fonts/a#b.otf")];
+
+        write_deps(&deps, "out.png", &temp, dependencies.into_iter()).unwrap();
+        assert_eq!(
+            fs::read_to_string(&deps).unwrap(),
+            "out.png: main\\ file.typ /fonts/a\\#b.otf\n"
+        );
+
+        fs::remove_dir_all(temp).ok();
+    }
+}
@@ -1,10 +1,26 @@
 use std::io::prelude::*;
-use std::str::FromStr;
-use std::{fs::File, path::PathBuf};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::{fmt, fs, fs::File, io, path::PathBuf};
 
+use ecow::{eco_format, EcoString};
 use serde::{Deserialize, Deserializer};
+use typst::foundations::Dict;
 
-use crate::args::{Input, ProcessArgs, WorldArgs};
+use crate::args::{
+    AnimationArgs, AtlasArgs, CompileArgs, DiagnosticFormat, Input, LiveArgs, Output, OutputFormat,
+    Pages, PresentArgs, ProcessArgs, RenderArgs, WorldArgs,
+};
+use crate::atlas;
+
+/// The config file that is read when none is given explicitly.
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// The resolution of exported images and animations when none is configured.
+pub const DEFAULT_EXPORT_PPI: f32 = 144.0;
+
+/// The resolution of live outputs when none is configured.
+pub const DEFAULT_LIVE_PPI: f32 = 216.0;
 
 pub fn deserialize_path<'de, D>(deserializer: D) -> Result<Input, D::Error>
 where
@@ -13,17 +29,62 @@ where
     let s = String::deserialize(deserializer)?;
 
     match s.as_str() {
+        "" => Err(serde::de::Error::custom("input path must not be empty")),
         "stdin" => Ok(Input::Stdin),
-        path => Ok(Input::Path(PathBuf::from_str(path).unwrap())),
+        path => Ok(Input::Path(PathBuf::from(path))),
+    }
+}
+
+pub fn deserialize_output<'de, D>(deserializer: D) -> Result<Option<Output>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+
+    match s.as_str() {
+        "" => Err(serde::de::Error::custom("output path must not be empty")),
+        "stdout" => Ok(Some(Output::Stdout)),
+        path => Ok(Some(Output::Path(PathBuf::from(path)))),
     }
 }
 
 #[derive(Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct SimulationConfig {
     #[serde(deserialize_with = "deserialize_path")]
     pub input: Input,
 
+    /// Path of the exported file, or `stdout`. When set, the document is
+    /// exported once instead of being published live. Use `{p}`, `{0p}` and
+    /// `{t}` in the path to export multiple pages as images.
+    #[serde(deserialize_with = "deserialize_output")]
+    pub output: Option<Output>,
+
+    /// The format of the exported file, inferred from the extension by
+    /// default.
+    pub format: Option<OutputFormat>,
+
+    /// The PPI (pixels per inch) to render images at. Defaults to 144 for
+    /// exports and animations and to 216 for live outputs.
+    pub ppi: Option<f32>,
+
+    /// File path to which a Makefile with the current compilation's
+    /// dependencies will be written.
+    pub make_deps: Option<PathBuf>,
+
+    /// Which pages to render, e.g. `["1-3", "5"]`. Defaults to all pages.
+    pub pages: Option<Vec<Pages>>,
+
+    pub render_config: RenderArgs,
+
+    pub present_config: PresentArgs,
+
+    pub live_config: LiveArgs,
+
+    pub animation_config: AnimationArgs,
+
+    pub atlas_config: AtlasArgs,
+
     pub process_config: ProcessArgs,
 
     pub world_config: WorldArgs,
@@ -33,22 +94,483 @@ impl Default for SimulationConfig {
     fn default() -> Self {
         Self {
             input: Input::Stdin,
+            output: None,
+            format: None,
+            ppi: None,
+            make_deps: None,
+            pages: None,
+            render_config: RenderArgs::default(),
+            present_config: PresentArgs::default(),
+            live_config: LiveArgs::default(),
+            animation_config: AnimationArgs::default(),
+            atlas_config: AtlasArgs::default(),
             process_config: ProcessArgs::default(),
             world_config: WorldArgs::default(),
         }
     }
 }
 
-pub fn load_config(path: &str) -> SimulationConfig {
-    let config_str = open_config_file(path).expect("Couldn't open file at the given path");
+impl SimulationConfig {
+    /// Overrides the values read from the config file with the ones given on
+    /// the command line.
+    pub fn apply_args(&mut self, args: CompileArgs) {
+        if let Some(input) = args.input {
+            self.input = input;
+        }
+        if args.output.is_some() {
+            self.output = args.output;
+        }
+        if args.format.is_some() {
+            self.format = args.format;
+        }
+        if args.pages.is_some() {
+            self.pages = args.pages;
+        }
+        if args.ppi.is_some() {
+            self.ppi = args.ppi;
+        }
+        if args.make_deps.is_some() {
+            self.make_deps = args.make_deps;
+        }
+        self.world_config.merge(args.world);
+        self.render_config.merge(args.render);
+        self.present_config.merge(args.present);
+        self.live_config.merge(args.live);
+        self.animation_config.merge(args.animation);
+        self.atlas_config.merge(args.atlas);
+        self.process_config.merge(args.process);
+    }
 
-    toml::from_str(&config_str).unwrap()
+    /// Checks that the configuration, including values given on the command
+    /// line, can be used for compiling.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &'static str, message: &str| {
+            Err(ConfigError::Invalid {
+                key,
+                message: message.into(),
+            })
+        };
+
+        if let Input::Path(path) = &self.input {
+            if !path.exists() {
+                return invalid(
+                    "input",
+                    &format!("input file not found (searched at {})", path.display()),
+                );
+            }
+        }
+        if self.make_deps.is_some() && !matches!(self.output, Some(Output::Path(_))) {
+            return invalid("make_deps", "needs an output path to name as the target");
+        }
+        if let Some(ppi) = self.ppi {
+            if !(ppi.is_finite() && ppi > 0.0) {
+                return invalid("ppi", "must be greater than zero");
+            }
+        }
+        if self.process_config.jobs == Some(0) {
+            return invalid("process_config.jobs", "must be at least one");
+        }
+        if let Some(interval) = self.present_config.interval {
+            if !(interval.is_finite() && interval > 0.0) {
+                return invalid("present_config.interval", "must be greater than zero");
+            }
+        }
+        if let Some(padding) = self.render_config.padding {
+            if self.render_config.element.is_none() {
+                return invalid("render_config.padding", "needs an element to pad");
+            }
+            if !(padding.is_finite() && padding >= 0.0) {
+                return invalid("render_config.padding", "must not be negative");
+            }
+        }
+        if self.process_config.timings_compiles.is_some() && self.process_config.timings.is_none() {
+            return invalid(
+                "process_config.timings_compiles",
+                "needs a timings path to write to",
+            );
+        }
+        if let Some(fps) = self.animation_config.fps {
+            if !(fps.is_finite() && fps > 0.0) {
+                return invalid("animation_config.fps", "must be greater than zero");
+            }
+        }
+        if let Some(duration) = self.animation_config.duration {
+            if !(duration.is_finite() && duration > 0.0) {
+                return invalid("animation_config.duration", "must be greater than zero");
+            }
+        }
+        if self.atlas_config.columns.is_some() && !self.atlas_config.rects.is_empty() {
+            return invalid("atlas_config", "set either `columns` or `rects`, not both");
+        }
+        if let Err(message) = atlas::check_rects(&self.atlas_config.rects) {
+            return invalid("atlas_config.rects", &message);
+        }
+        if let Some(url) = &self.live_config.websocket {
+            if !url.starts_with("ws://") && !url.starts_with("wss://") {
+                return invalid("live_config.websocket", "must be a ws:// or wss:// URL");
+            }
+        }
+        if let Some(root) = &self.world_config.root {
+            if !root.is_dir() {
+                return invalid(
+                    "world_config.root",
+                    &format!("root directory not found (searched at {})", root.display()),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether switching to the `other` configuration requires a new world, as
+    /// opposed to reconfiguring the existing one in place.
+    pub fn needs_new_world(&self, other: &SimulationConfig) -> bool {
+        self.input != other.input
+            || self.world_config.root != other.world_config.root
+            || self.world_config.font != other.world_config.font
+            || self.world_config.package != other.world_config.package
+    }
+
+    /// The format to emit diagnostics in.
+    pub fn diagnostic_format(&self) -> DiagnosticFormat {
+        self.process_config.diagnostic_format.unwrap_or_default()
+    }
+
+    /// The resolution of exported images and animations.
+    pub fn export_ppi(&self) -> f32 {
+        self.ppi.unwrap_or(DEFAULT_EXPORT_PPI)
+    }
+
+    /// The resolution of live outputs.
+    pub fn live_ppi(&self) -> f32 {
+        self.ppi.unwrap_or(DEFAULT_LIVE_PPI)
+    }
+
+    /// Whether the page at the given zero-based index is selected by `pages`.
+    pub fn includes_page(&self, index: usize) -> bool {
+        match &self.pages {
+            Some(ranges) => {
+                let number = NonZeroUsize::new(index + 1).unwrap();
+                ranges.iter().any(|range| range.includes(number))
+            }
+            None => true,
+        }
+    }
 }
 
-fn open_config_file(path: &str) -> std::io::Result<String> {
+/// Where a configuration came from, so that it can be loaded again when the
+/// config file changes.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSource {
+    /// The config file, if one is used.
+    pub path: Option<PathBuf>,
+    /// Command-line arguments that override the config file.
+    pub args: Option<CompileArgs>,
+}
+
+impl ConfigSource {
+    /// Loads the config file, applies the command-line arguments and
+    /// validates the result. Reads the input file, if one is configured.
+    pub fn load(&self) -> Result<SimulationConfig, ConfigError> {
+        let mut config = load_config(self.path.as_deref())?;
+        if let Some(args) = &self.args {
+            config.apply_args(args.clone());
+        }
+        config.validate()?;
+        load_input_file(&mut config.world_config)?;
+        Ok(config)
+    }
+}
+
+/// Puts the entries of the JSON input file in front of the other inputs, so
+/// that inputs given directly take precedence.
+fn load_input_file(world_args: &mut WorldArgs) -> Result<(), ConfigError> {
+    let Some(path) = &world_args.input_file else {
+        return Ok(());
+    };
+
+    let error = |message: String| ConfigError::InputFile {
+        path: path.clone(),
+        message: message.into(),
+    };
+    let data = fs::read(path).map_err(|err| error(err.to_string()))?;
+    let dict: Dict = serde_json::from_slice(&data).map_err(|err| error(err.to_string()))?;
+
+    let mut inputs: Vec<_> = dict
+        .into_iter()
+        .map(|(key, value)| (key.as_str().into(), value))
+        .collect();
+    inputs.append(&mut world_args.inputs);
+    world_args.inputs = inputs;
+
+    Ok(())
+}
+
+/// Returns the given config path, or the default config file if it exists.
+pub fn resolve_path(path: Option<&Path>) -> Option<PathBuf> {
+    match path {
+        Some(path) => Some(path.to_path_buf()),
+        None => {
+            let default = Path::new(DEFAULT_CONFIG_PATH);
+            default.exists().then(|| default.to_path_buf())
+        }
+    }
+}
+
+/// Loads the config file at the given path. Without one, the default
+/// configuration is used.
+pub fn load_config(path: Option<&Path>) -> Result<SimulationConfig, ConfigError> {
+    match path {
+        Some(path) => parse_config(path),
+        None => Ok(SimulationConfig::default()),
+    }
+}
+
+/// Reads and parses the config file at the given path.
+fn parse_config(path: &Path) -> Result<SimulationConfig, ConfigError> {
+    let config_str = open_config_file(path).map_err(|err| ConfigError::Io {
+        path: path.to_path_buf(),
+        err,
+    })?;
+    parse_config_str(path, &config_str)
+}
+
+/// Parses the contents of the config file at the given path.
+fn parse_config_str(path: &Path, config_str: &str) -> Result<SimulationConfig, ConfigError> {
+    toml::from_str(config_str).map_err(|err| {
+        // Turn the byte span of the error into a human-readable position.
+        let (line, column) = err
+            .span()
+            .map(|span| {
+                let before = &config_str[..span.start];
+                let line = before.matches('\n').count() + 1;
+                let column = before
+                    .rsplit('\n')
+                    .next()
+                    .unwrap_or_default()
+                    .chars()
+                    .count()
+                    + 1;
+                (line, column)
+            })
+            .unwrap_or((1, 1));
+
+        ConfigError::Parse {
+            path: path.to_path_buf(),
+            line,
+            column,
+            message: err.message().into(),
+        }
+    })
+}
+
+fn open_config_file(path: &Path) -> std::io::Result<String> {
     let mut file = File::open(path)?;
     let mut contents = String::new();
     file.read_to_string(&mut contents)?;
 
     Ok(contents)
 }
+
+/// An error that occurs while loading or validating the configuration.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config file couldn't be read.
+    Io { path: PathBuf, err: io::Error },
+    /// The config file isn't valid TOML or doesn't match the expected
+    /// structure, e.g. because of an unknown key.
+    Parse {
+        path: PathBuf,
+        line: usize,
+        column: usize,
+        message: EcoString,
+    },
+    /// The input file couldn't be read or doesn't hold a JSON object.
+    InputFile { path: PathBuf, message: EcoString },
+    /// A value is out of range or points to something that doesn't exist.
+    Invalid {
+        key: &'static str,
+        message: EcoString,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, err } => {
+                write!(f, "failed to read config file {} ({err})", path.display())
+            }
+            ConfigError::Parse {
+                path,
+                line,
+                column,
+                message,
+            } => {
+                write!(f, "{}:{line}:{column}: {message}", path.display())
+            }
+            ConfigError::InputFile { path, message } => {
+                write!(
+                    f,
+                    "failed to read input file {} ({message})",
+                    path.display()
+                )
+            }
+            ConfigError::Invalid { key, message } => write!(f, "invalid `{key}`: {message}"),
+        }
+    }
+}
+
+impl From<ConfigError> for EcoString {
+    fn from(err: ConfigError) -> Self {
+        eco_format!("{err}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use typst::foundations::IntoValue;
+
+    use super::*;
+    use crate::args::{CliArguments, Command};
+
+    #[test]
+    fn reports_parse_errors_with_position() {
+        let err = parse("ppi = 144\ninput = 5\n").err().unwrap();
+        assert!(matches!(
+            err,
+            ConfigError::Parse {
+                line: 2,
+                column: 9,
+                ..
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "config.toml:2:9: invalid type: integer `5`, expected a string"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        let err = parse("[present_config]\nintervall = 2\n").err().unwrap();
+        assert!(matches!(
+            err,
+            ConfigError::Parse {
+                line: 2,
+                column: 1,
+                ..
+            }
+        ));
+        assert!(err.to_string().contains("unknown field `intervall`"));
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let cases = [
+            ("input = \"missing.typ\"", "input"),
+            ("ppi = 0", "ppi"),
+            ("ppi = nan", "ppi"),
+            ("[process_config]\njobs = 0", "process_config.jobs"),
+            ("[present_config]\ninterval = -1.0", "present_config.interval"),
+            ("[animation_config]\nfps = 0", "animation_config.fps"),
+            (
+                "[atlas_config]\ncolumns = 2\n\
+                 rects = [{ page = 1, x = 0, y = 0, width = 1, height = 1 }]",
+                "atlas_config",
+            ),
+            (
+                "[atlas_config]\nrects = [\n  { page = 1, x = 0, y = 0, width = 4, height = 4 },\n  \
+                 { page = 2, x = 2, y = 2, width = 4, height = 4 },\n]",
+                "atlas_config.rects",
+            ),
+            ("[live_config]\nwebsocket = \"http://localhost\"", "live_config.websocket"),
+        ];
+        for (source, expected) in cases {
+            let config = parse(source).unwrap();
+            match config.validate() {
+                Err(ConfigError::Invalid { key, .. }) => assert_eq!(key, expected, "{source}"),
+                result => panic!("{source} was validated as {result:?}"),
+            }
+        }
+
+        let valid =
+            "ppi = 72\n[process_config]\njobs = 2\n[live_config]\nwebsocket = \"ws://localhost\"";
+        parse(valid).unwrap().validate().unwrap();
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let mut config = parse(
+            "input = \"config.typ\"\nppi = 144\n\
+             [world_config]\ninputs = { mode = \"config\", theme = \"dark\" }\n\
+             [process_config]\njobs = 1\n",
+        )
+        .unwrap();
+        let cli = CliArguments::try_parse_from([
+            "typst-render",
+            "compile",
+            "cli.typ",
+            "--ppi",
+            "72",
+            "--jobs",
+            "3",
+            "--input",
+            "mode=cli",
+        ])
+        .unwrap();
+        let Some(Command::Compile(command)) = cli.command else {
+            panic!("expected the compile command");
+        };
+
+        config.apply_args(command.args);
+        assert_eq!(config.input, Input::Path("cli.typ".into()));
+        assert_eq!(config.ppi, Some(72.0));
+        assert_eq!(config.process_config.jobs, Some(3));
+
+        // Inputs from the command line are added after the configured ones,
+        // so that they take precedence.
+        let inputs = &config.world_config.inputs;
+        assert_eq!(inputs.len(), 3);
+        assert_eq!(inputs[2], ("mode".into(), "cli".into_value()));
+    }
+
+    #[test]
+    fn needs_new_world_for_world_changes() {
+        let config = parse("ppi = 72\n[world_config]\ninputs = { mode = \"a\" }").unwrap();
+        let in_place = [
+            "ppi = 96\n[world_config]\ninputs = { mode = \"a\" }",
+            "ppi = 72\n[world_config]\ninputs = { mode = \"b\" }",
+        ];
+        for source in in_place {
+            assert!(!config.needs_new_world(&parse(source).unwrap()), "{source}");
+        }
+
+        let recreate = [
+            "input = \"other.typ\"",
+            "[world_config]\nroot = \"other\"",
+            "[world_config.font]\nfont_paths = [\"fonts\"]",
+            "[world_config.font]\nignore_system_fonts = true",
+            "[world_config.package]\npackage_path = \"packages\"",
+        ];
+        for source in recreate {
+            assert!(config.needs_new_world(&parse(source).unwrap()), "{source}");
+        }
+    }
+
+    #[test]
+    fn includes_selected_pages() {
+        let config = SimulationConfig {
+            pages: Some(vec!["1-3".parse().unwrap(), "5".parse().unwrap()]),
+            ..SimulationConfig::default()
+        };
+        let included: Vec<_> = (0..7).filter(|&i| config.includes_page(i)).collect();
+        assert_eq!(included, [0, 1, 2, 4]);
+        assert!(SimulationConfig::default().includes_page(99));
+    }
+
+    /// Parses a config file with the given contents.
+    fn parse(source: &str) -> Result<SimulationConfig, ConfigError> {
+        parse_config_str(Path::new("config.toml"), source)
+    }
+}
@@ -1,23 +1,303 @@
-use std::fmt::Display;
-use typst_kit::download::{DownloadState, Downloader, Progress};
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-/// Prints download progress by writing `downloading {0}` followed by repeatedly
-/// updating the last terminal line.
-pub struct PrintDownload<T>(pub T);
+use color_print::ceprintln;
+use serde::Serialize;
 
-impl<T: Display> Progress for PrintDownload<T> {
-    fn print_start(&mut self) {}
+use crate::args::PackageArgs;
 
-    fn print_progress(&mut self, state: &DownloadState) {}
+/// The user agent packages are downloaded with.
+const USER_AGENT: &str = concat!("typst/", env!("CARGO_PKG_VERSION"));
 
-    fn print_finish(&mut self, state: &DownloadState) {}
+/// How many one-second samples the reported download speed averages.
+const SPEED_SAMPLES: usize = 5;
+
+/// Receives the events of package downloads, e.g. to show progress in a GUI.
+pub type DownloadListener = Arc<dyn Fn(&DownloadEvent) + Send + Sync>;
+
+/// Something that happened while downloading a package.
+///
+/// Serialized with an `event` field naming the variant, which is how events
+/// are printed when standard error isn't a terminal.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DownloadEvent {
+    /// A download started.
+    Start {
+        /// The package that is downloaded, e.g. `@preview/example:0.1.0`.
+        package: String,
+    },
+    /// More data arrived.
+    Progress(DownloadProgress),
+    /// The download completed.
+    Finish(DownloadProgress),
+}
+
+/// The state of a running download.
+#[derive(Debug, Clone, Serialize)]
+pub struct DownloadProgress {
+    /// The package that is downloaded.
+    pub package: String,
+    /// The number of bytes downloaded so far.
+    pub downloaded: usize,
+    /// The size of the download, if the server announced it.
+    pub total: Option<usize>,
+    /// The average download speed over the last few seconds.
+    pub bytes_per_second: usize,
+    /// The time since the download started, in seconds.
+    pub elapsed: f64,
+}
+
+impl DownloadProgress {
+    /// How much of the download is done, between 0 and 100.
+    pub fn percent(&self) -> Option<f64> {
+        self.total
+            .filter(|&total| total > 0)
+            .map(|total| (self.downloaded as f64 / total as f64 * 100.0).min(100.0))
+    }
+
+    /// The estimated time until the download completes.
+    pub fn eta(&self) -> Option<Duration> {
+        let remaining = self.total?.saturating_sub(self.downloaded);
+        (self.bytes_per_second > 0)
+            .then(|| Duration::from_secs((remaining / self.bytes_per_second) as u64))
+    }
+}
+
+/// Downloads packages, trusting the configured CA certificate and going
+/// through the configured proxy.
+#[derive(Debug, Clone)]
+pub struct Downloader {
+    /// A CA certificate (PEM) to trust in addition to the system's, if any.
+    cert: Option<PathBuf>,
+    /// The proxy to download through. Without one, the proxy environment
+    /// variables apply.
+    proxy: Option<String>,
 }
 
-/// Returns a new downloader.
-pub fn downloader() -> Downloader {
-    let user_agent = concat!("typst/", env!("CARGO_PKG_VERSION"));
-    // There's a chance we need to provide a cert
-    // Refer to typst-cli source to see how to do it
+impl Downloader {
+    pub fn new(args: &PackageArgs) -> Self {
+        Self {
+            cert: args.cert.clone(),
+            proxy: args.proxy.clone(),
+        }
+    }
+
+    /// Downloads the file at the URL and reports the progress of the
+    /// download of the package to a listener.
+    pub fn download(
+        &self,
+        url: &str,
+        package: String,
+        listener: &DownloadListener,
+    ) -> io::Result<Vec<u8>> {
+        let response = self.agent()?.get(url).call().map_err(io::Error::other)?;
+        let total = response
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok());
+
+        let mut state = DownloadState::new(package, total);
+        listener(&DownloadEvent::Start {
+            package: state.package.clone(),
+        });
+
+        let mut data = Vec::with_capacity(total.unwrap_or(0));
+        let mut reader = response.into_reader();
+        let mut buf = [0; 8192];
+        loop {
+            let read = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            data.extend_from_slice(&buf[..read]);
+            if state.advance(read) {
+                listener(&DownloadEvent::Progress(state.progress()));
+            }
+        }
+
+        listener(&DownloadEvent::Finish(state.progress()));
+        Ok(data)
+    }
+
+    /// Builds an HTTP agent with the certificate and proxy.
+    fn agent(&self) -> io::Result<ureq::Agent> {
+        let mut tls = native_tls::TlsConnector::builder();
+        if let Some(path) = &self.cert {
+            let pem = fs::read(path).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("failed to read certificate {} ({err})", path.display()),
+                )
+            })?;
+            tls.add_root_certificate(
+                native_tls::Certificate::from_pem(&pem).map_err(io::Error::other)?,
+            );
+        }
+
+        let builder = ureq::AgentBuilder::new()
+            .user_agent(USER_AGENT)
+            .tls_connector(Arc::new(tls.build().map_err(io::Error::other)?));
+        let builder = match &self.proxy {
+            Some(proxy) => builder.proxy(ureq::Proxy::new(proxy).map_err(io::Error::other)?),
+            None => builder.try_proxy_from_env(true),
+        };
+        Ok(builder.build())
+    }
+}
+
+/// Tracks a running download.
+struct DownloadState {
+    /// The package that is downloaded.
+    package: String,
+    /// The size of the download, if the server announced it.
+    total: Option<usize>,
+    /// The number of bytes downloaded so far.
+    downloaded: usize,
+    /// When the download started.
+    start: Instant,
+    /// When the current one-second sample started.
+    sample_start: Instant,
+    /// The number of bytes downloaded in the current sample.
+    sample_bytes: usize,
+    /// The speeds of the last few samples in bytes per second, newest first.
+    speeds: VecDeque<usize>,
+}
+
+impl DownloadState {
+    fn new(package: String, total: Option<usize>) -> Self {
+        let now = Instant::now();
+        Self {
+            package,
+            total,
+            downloaded: 0,
+            start: now,
+            sample_start: now,
+            sample_bytes: 0,
+            speeds: VecDeque::with_capacity(SPEED_SAMPLES),
+        }
+    }
+
+    /// Records downloaded bytes. Returns whether a sample completed, which is
+    /// when progress is reported.
+    fn advance(&mut self, bytes: usize) -> bool {
+        self.downloaded += bytes;
+        self.sample_bytes += bytes;
+
+        let elapsed = self.sample_start.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return false;
+        }
+
+        if self.speeds.len() == SPEED_SAMPLES {
+            self.speeds.pop_back();
+        }
+        let speed = self.sample_bytes as f64 / elapsed.as_secs_f64();
+        self.speeds.push_front(speed as usize);
+        self.sample_start = Instant::now();
+        self.sample_bytes = 0;
+        true
+    }
+
+    /// The progress to report.
+    fn progress(&self) -> DownloadProgress {
+        let bytes_per_second = match self.speeds.len() {
+            0 => (self.downloaded as f64 / self.start.elapsed().as_secs_f64().max(1e-3)) as usize,
+            samples => self.speeds.iter().sum::<usize>() / samples,
+        };
+
+        DownloadProgress {
+            package: self.package.clone(),
+            downloaded: self.downloaded,
+            total: self.total,
+            bytes_per_second,
+            elapsed: self.start.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+/// Returns a listener that prints download events to standard error.
+///
+/// On a terminal, `downloading {package}` is followed by a line with the
+/// downloaded bytes, percentage, speed and remaining time that is updated in
+/// place. Otherwise, every event is printed as one line of JSON.
+pub fn print_events() -> DownloadListener {
+    if io::stderr().is_terminal() {
+        Arc::new(print_human)
+    } else {
+        Arc::new(|event: &DownloadEvent| {
+            if let Ok(line) = serde_json::to_string(event) {
+                eprintln!("{line}");
+            }
+        })
+    }
+}
+
+/// Prints a download event for humans.
+fn print_human(event: &DownloadEvent) {
+    match event {
+        DownloadEvent::Start { package } => ceprintln!("<cyan,bold>downloading</> {}", package),
+        DownloadEvent::Progress(progress) => {
+            // Overwrite the previous progress line.
+            let mut err = io::stderr().lock();
+            write!(err, "\r\x1b[2K{}", describe_progress(progress)).ok();
+            err.flush().ok();
+        }
+        DownloadEvent::Finish(progress) => {
+            eprintln!("\r\x1b[2K{}", describe_progress(progress));
+        }
+    }
+}
+
+/// Describes the state of a download in one line.
+fn describe_progress(progress: &DownloadProgress) -> String {
+    let downloaded = format_bytes(progress.downloaded);
+    let speed = format_bytes(progress.bytes_per_second);
+    let elapsed = format_duration(Duration::from_secs_f64(progress.elapsed));
+
+    match (progress.total, progress.percent()) {
+        (Some(total), Some(percent)) => {
+            let eta = progress
+                .eta()
+                .map(format_duration)
+                .unwrap_or_else(|| "unknown".into());
+            format!(
+                "{downloaded} / {} ({percent:3.0} %) {speed}/s in {elapsed} ETA: {eta}",
+                format_bytes(total),
+            )
+        }
+        _ => format!("{downloaded} at {speed}/s in {elapsed}"),
+    }
+}
+
+/// Formats a number of bytes with a binary unit.
+fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
 
-    Downloader::new(user_agent)
+/// Formats a duration as seconds, or minutes and seconds.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs < 60 {
+        format!("{:.1} s", duration.as_secs_f64())
+    } else {
+        format!("{} min {} s", secs / 60, secs % 60)
+    }
 }
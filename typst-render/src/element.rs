@@ -0,0 +1,199 @@
+use ecow::eco_format;
+use typst::diag::StrResult;
+use typst::introspection::{Location, Tag};
+use typst::layout::{Abs, Frame, FrameItem, Page, PagedDocument, Point, Size, Transform};
+
+/// Finds the element with the given label and returns a page that shows just
+/// that element, with `padding` around its bounding box.
+///
+/// The page keeps its fill, so the background can be replaced like for whole
+/// pages. If the element breaks across pages, its part on the first page is
+/// used.
+pub fn crop_to_element(document: &PagedDocument, label: &str, padding: Abs) -> StrResult<Page> {
+    for page in &document.pages {
+        let mut finder = BoundsFinder::new(label);
+        finder.visit(&page.frame, Transform::identity());
+        if !finder.found {
+            continue;
+        }
+
+        let Some((min, max)) = finder.bounds else {
+            return Err(eco_format!("element <{label}> has no visible content"));
+        };
+
+        let origin = min - Point::splat(padding);
+        let size = Size::new(max.x - min.x, max.y - min.y) + Size::splat(2.0 * padding);
+
+        let mut frame = Frame::hard(size);
+        frame.push_frame(-origin, page.frame.clone());
+        return Ok(Page {
+            frame,
+            ..page.clone()
+        });
+    }
+
+    Err(eco_format!("found no element labelled <{label}>"))
+}
+
+/// Collects the bounding box of a labelled element in page coordinates.
+///
+/// Elements are delimited by start and end tags in the frames, which are
+/// visited in document order. Everything between the two tags belongs to the
+/// element, even if the tags are in different groups.
+struct BoundsFinder<'a> {
+    /// The label to look for.
+    label: &'a str,
+    /// Whether the start tag of the element was seen.
+    found: bool,
+    /// The location of the element while its content is visited.
+    inside: Option<Location>,
+    /// Whether the end tag was seen.
+    done: bool,
+    /// The top-left and bottom-right corner of the content seen so far.
+    bounds: Option<(Point, Point)>,
+}
+
+impl<'a> BoundsFinder<'a> {
+    fn new(label: &'a str) -> Self {
+        Self {
+            label,
+            found: false,
+            inside: None,
+            done: false,
+            bounds: None,
+        }
+    }
+
+    /// Visits the items of a frame that is placed with the given transform.
+    fn visit(&mut self, frame: &Frame, ts: Transform) {
+        for (pos, item) in frame.items() {
+            if self.done {
+                return;
+            }
+
+            let ts = ts.pre_concat(Transform::translate(pos.x, pos.y));
+            match item {
+                FrameItem::Tag(Tag::Start(elem)) if self.inside.is_none() => {
+                    let labelled = elem
+                        .label()
+                        .is_some_and(|label| label.resolve().as_str() == self.label);
+                    if labelled {
+                        self.found = true;
+                        self.inside = elem.location();
+                    }
+                }
+                FrameItem::Tag(Tag::End(location, _)) if self.inside == Some(*location) => {
+                    self.inside = None;
+                    self.done = true;
+                }
+                FrameItem::Tag(_) => {}
+                FrameItem::Group(group) => {
+                    let ts = ts.pre_concat(group.transform);
+                    if self.inside.is_some() {
+                        self.include(Point::zero(), group.frame.size().to_point(), ts);
+                    }
+                    self.visit(&group.frame, ts);
+                }
+                _ if self.inside.is_none() => {}
+                FrameItem::Text(text) => {
+                    let metrics = text.font.metrics();
+                    let top = -metrics.ascender.at(text.size);
+                    let bottom = -metrics.descender.at(text.size);
+                    self.include(Point::with_y(top), Point::new(text.width(), bottom), ts);
+                }
+                FrameItem::Shape(shape, _) => {
+                    self.include(Point::zero(), shape.geometry.bbox_size().to_point(), ts);
+                }
+                FrameItem::Image(_, size, _) | FrameItem::Link(_, size) => {
+                    self.include(Point::zero(), size.to_point(), ts);
+                }
+            }
+        }
+    }
+
+    /// Extends the bounds by a rectangle that is placed with the given
+    /// transform.
+    fn include(&mut self, min: Point, max: Point, ts: Transform) {
+        let corners = [min, Point::new(max.x, min.y), Point::new(min.x, max.y), max];
+        for corner in corners.map(|corner| corner.transform(ts)) {
+            let (lo, hi) = self.bounds.get_or_insert((corner, corner));
+            *lo = lo.min(corner);
+            *hi = hi.max(corner);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::args::{FontArgs, Input, ProcessArgs, WorldArgs};
+    use crate::world::SystemWorld;
+
+    #[test]
+    fn crops_to_padded_bounding_box() {
+        let document = compile(
+            "crop",
+            "#place(dx: 10pt, dy: 20pt)[#rect(width: 30pt, height: 40pt) <box>]",
+        );
+        let page = crop_to_element(&document, "box", Abs::pt(5.0)).unwrap();
+        assert_eq!(page.frame.size(), Size::new(Abs::pt(40.0), Abs::pt(50.0)));
+        assert_eq!(origin(&page), Point::new(Abs::pt(-5.0), Abs::pt(-15.0)));
+    }
+
+    #[test]
+    fn crops_to_first_of_several_matches() {
+        let document = compile(
+            "matches",
+            "#place(dx: 10pt)[#rect(width: 30pt, height: 40pt) <box>]\n\
+             #place(dx: 50pt)[#rect(width: 20pt, height: 20pt) <box>]",
+        );
+        let page = crop_to_element(&document, "box", Abs::zero()).unwrap();
+        assert_eq!(page.frame.size(), Size::new(Abs::pt(30.0), Abs::pt(40.0)));
+        assert_eq!(origin(&page), Point::new(Abs::pt(-10.0), Abs::zero()));
+    }
+
+    #[test]
+    fn fails_without_labelled_element() {
+        let document = compile("missing", "#rect() <box>");
+        let err = crop_to_element(&document, "scoreboard", Abs::zero()).unwrap_err();
+        assert_eq!(err, "found no element labelled <scoreboard>");
+    }
+
+    /// Compiles a document on pages without margins.
+    fn compile(name: &str, body: &str) -> PagedDocument {
+        let temp = std::env::temp_dir().join(format!(
+            "typst-render-element-{name}-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&temp).unwrap();
+        let main = temp.join("main.typ");
+        fs::write(
+            &main,
+            format!("#set page(margin: 0pt)\n#set rect(stroke: none, fill: black)\n{body}"),
+        )
+        .unwrap();
+
+        let world_args = WorldArgs {
+            root: Some(temp.clone()),
+            font: FontArgs {
+                ignore_system_fonts: true,
+                ..FontArgs::default()
+            },
+            ..WorldArgs::default()
+        };
+        let world =
+            SystemWorld::new(&Input::Path(main), &world_args, &ProcessArgs::default()).unwrap();
+        let document = typst::compile::<PagedDocument>(&world).output.unwrap();
+
+        fs::remove_dir_all(temp).ok();
+        document
+    }
+
+    /// Where the page's content is placed in the cropped page.
+    fn origin(page: &Page) -> Point {
+        let (pos, _) = page.frame.items().next().unwrap();
+        *pos
+    }
+}
@@ -0,0 +1,312 @@
+use std::path::Path;
+
+use chrono::{Datelike, Timelike};
+use ecow::{eco_format, EcoString};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use typst::diag::{bail, StrResult};
+use typst::foundations::{Datetime, Smart};
+use typst::html::HtmlDocument;
+use typst::layout::{Page, PageRanges, PagedDocument};
+use typst_pdf::{PdfOptions, PdfStandards, Timestamp};
+
+use crate::args::{Feature, Output, OutputFormat};
+use crate::compile::{compile_document, print_diagnostics, write_make_deps};
+use crate::config::SimulationConfig;
+use crate::render::{encode_png, render_page, selected_pages, with_background, Renderer};
+use crate::world::SystemWorld;
+
+/// Compiles the document once and exports it to the configured output.
+pub fn export_once(world: &mut SystemWorld, config: &SimulationConfig) -> StrResult<()> {
+    let Some(output) = &config.output else {
+        bail!("no output was configured");
+    };
+    let format = output_format(output, config.format)?;
+
+    if format == OutputFormat::Html {
+        if !config.process_config.features.contains(&Feature::Html) {
+            bail!("HTML export is only available when the `html` feature is enabled (e.g. with `--features html`)");
+        }
+        let document = compile_document::<HtmlDocument>(world, config.diagnostic_format())?;
+        export_html(world, &document, output, config)?;
+    } else {
+        let document = compile_document::<PagedDocument>(world, config.diagnostic_format())?;
+        export(world, &document, output, format, config)?;
+    }
+    write_make_deps(world, config)
+}
+
+/// Determines the output format, inferring it from the output path's
+/// extension if it wasn't set explicitly.
+pub fn output_format(output: &Output, format: Option<OutputFormat>) -> StrResult<OutputFormat> {
+    if let Some(specified) = format {
+        return Ok(specified);
+    }
+
+    let Output::Path(path) = output else {
+        return Ok(OutputFormat::Pdf);
+    };
+
+    Ok(match path.extension() {
+        Some(ext) if ext.eq_ignore_ascii_case("pdf") => OutputFormat::Pdf,
+        Some(ext) if ext.eq_ignore_ascii_case("png") => OutputFormat::Png,
+        Some(ext) if ext.eq_ignore_ascii_case("svg") => OutputFormat::Svg,
+        Some(ext) if ext.eq_ignore_ascii_case("html") => OutputFormat::Html,
+        _ => bail!(
+            "could not infer output format for path {}.\nconsider setting `format` explicitly",
+            path.display(),
+        ),
+    })
+}
+
+/// Export the compiled document into the given paged format.
+pub fn export(
+    world: &SystemWorld,
+    document: &PagedDocument,
+    output: &Output,
+    format: OutputFormat,
+    config: &SimulationConfig,
+) -> StrResult<()> {
+    match format {
+        OutputFormat::Pdf => export_pdf(world, document, output, config),
+        OutputFormat::Png if config.atlas_config.enabled() => {
+            export_atlas(document, output, config)
+        }
+        OutputFormat::Svg if config.atlas_config.enabled() => {
+            bail!("atlases can only be exported as PNG")
+        }
+        OutputFormat::Png => export_image(document, output, ImageExportFormat::Png, config),
+        OutputFormat::Svg => export_image(document, output, ImageExportFormat::Svg, config),
+        OutputFormat::Html => unreachable!("HTML is exported from an HTML document"),
+    }
+}
+
+/// Export to an HTML file.
+fn export_html(
+    world: &SystemWorld,
+    document: &HtmlDocument,
+    output: &Output,
+    config: &SimulationConfig,
+) -> StrResult<()> {
+    let html = typst_html::html(document).map_err(|errors| {
+        print_diagnostics(world, &errors, &[], config.diagnostic_format()).ok();
+        eco_format!("failed to export HTML")
+    })?;
+
+    output
+        .write(html.as_bytes())
+        .map_err(|err| eco_format!("failed to write HTML file ({err})"))
+}
+
+/// Export to a PDF.
+fn export_pdf(
+    world: &SystemWorld,
+    document: &PagedDocument,
+    output: &Output,
+    config: &SimulationConfig,
+) -> StrResult<()> {
+    let timestamp = config
+        .world_config
+        .creation_timestamp
+        .unwrap_or_else(chrono::Utc::now);
+
+    let options = PdfOptions {
+        ident: Smart::Auto,
+        timestamp: convert_datetime(timestamp).map(Timestamp::new_utc),
+        page_ranges: config
+            .pages
+            .as_ref()
+            .map(|pages| PageRanges::new(pages.iter().map(|p| p.0.clone()).collect())),
+        standards: PdfStandards::default(),
+    };
+
+    let buffer = typst_pdf::pdf(document, &options).map_err(|errors| {
+        print_diagnostics(world, &errors, &[], config.diagnostic_format()).ok();
+        eco_format!("failed to export PDF")
+    })?;
+
+    output
+        .write(&buffer)
+        .map_err(|err| eco_format!("failed to write PDF file ({err})"))
+}
+
+/// Export the pages packed into one PNG image.
+fn export_atlas(
+    document: &PagedDocument,
+    output: &Output,
+    config: &SimulationConfig,
+) -> StrResult<()> {
+    let atlas = Renderer::default().render_document(document, config.export_ppi(), config)?;
+    let space = config.render_config.color_space.unwrap_or_default();
+    let buf = encode_png(&atlas[0].pixmap, space)?;
+    output
+        .write(&buf)
+        .map_err(|err| eco_format!("failed to write PNG file ({err})"))
+}
+
+/// Convert [`chrono::DateTime`] to [`Datetime`]
+fn convert_datetime(date_time: chrono::DateTime<chrono::Utc>) -> Option<Datetime> {
+    Datetime::from_ymd_hms(
+        date_time.year(),
+        date_time.month().try_into().ok()?,
+        date_time.day().try_into().ok()?,
+        date_time.hour().try_into().ok()?,
+        date_time.minute().try_into().ok()?,
+        date_time.second().try_into().ok()?,
+    )
+}
+
+/// An image format to export in.
+#[derive(Copy, Clone)]
+enum ImageExportFormat {
+    Png,
+    Svg,
+}
+
+/// Export to one or multiple images.
+fn export_image(
+    document: &PagedDocument,
+    output: &Output,
+    fmt: ImageExportFormat,
+    config: &SimulationConfig,
+) -> StrResult<()> {
+    // Determine whether we have indexable templates in output
+    let can_handle_multiple = match output {
+        Output::Stdout => false,
+        Output::Path(path) => {
+            output_template::has_indexable_template(path.to_str().unwrap_or_default())
+        }
+    };
+
+    let exported_pages = selected_pages(document, config)?;
+
+    if !can_handle_multiple && exported_pages.len() > 1 {
+        let err = match output {
+            Output::Stdout => "to stdout",
+            Output::Path(_) => "without a page number template ({p}, {0p}) in the output path",
+        };
+        bail!("cannot export multiple images {err}");
+    }
+
+    exported_pages
+        .par_iter()
+        .map(|(i, page)| {
+            // Use output with converted path.
+            let output = match output {
+                Output::Path(path) => {
+                    let storage;
+                    let path = if can_handle_multiple {
+                        storage = output_template::format(
+                            path.to_str().unwrap_or_default(),
+                            i + 1,
+                            document.pages.len(),
+                        );
+                        Path::new(&storage)
+                    } else {
+                        path
+                    };
+
+                    Output::Path(path.to_owned())
+                }
+                Output::Stdout => Output::Stdout,
+            };
+
+            export_image_page(page, &output, fmt, config)
+        })
+        .collect::<Result<Vec<()>, EcoString>>()?;
+
+    Ok(())
+}
+
+pub mod output_template {
+    const INDEXABLE: [&str; 3] = ["{p}", "{0p}", "{n}"];
+
+    pub fn has_indexable_template(output: &str) -> bool {
+        INDEXABLE.iter().any(|index| output.contains(index))
+    }
+
+    pub fn format(output: &str, this_page: usize, total_pages: usize) -> String {
+        // Find the base 10 width of number `i`
+        fn width(i: usize) -> usize {
+            1 + i.checked_ilog10().unwrap_or(0) as usize
+        }
+
+        let other_templates = ["{t}"];
+        INDEXABLE
+            .iter()
+            .chain(other_templates.iter())
+            .fold(output.to_string(), |out, template| {
+                let replacement = match *template {
+                    "{p}" => format!("{this_page}"),
+                    "{0p}" | "{n}" => format!("{:01$}", this_page, width(total_pages)),
+                    "{t}" => format!("{total_pages}"),
+                    _ => unreachable!("unhandled template placeholder {template}"),
+                };
+                out.replace(template, replacement.as_str())
+            })
+    }
+}
+
+/// Export single image.
+fn export_image_page(
+    page: &Page,
+    output: &Output,
+    fmt: ImageExportFormat,
+    config: &SimulationConfig,
+) -> StrResult<()> {
+    match fmt {
+        ImageExportFormat::Png => {
+            let pixmap = render_page(page, config.export_ppi(), &config.render_config)?;
+            let space = config.render_config.color_space.unwrap_or_default();
+            let buf = encode_png(&pixmap, space)?;
+            output
+                .write(&buf)
+                .map_err(|err| eco_format!("failed to write PNG file ({err})"))?;
+        }
+        ImageExportFormat::Svg => {
+            let svg = typst_svg::svg(&with_background(page, config.render_config.background));
+            output
+                .write(svg.as_bytes())
+                .map_err(|err| eco_format!("failed to write SVG file ({err})"))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn infers_format_from_extension() {
+        let format = |path: &str| output_format(&Output::Path(PathBuf::from(path)), None);
+        assert_eq!(format("out.pdf"), Ok(OutputFormat::Pdf));
+        assert_eq!(format("out-{p}.PNG"), Ok(OutputFormat::Png));
+        assert_eq!(format("out.svg"), Ok(OutputFormat::Svg));
+        assert_eq!(format("out.html"), Ok(OutputFormat::Html));
+        assert!(format("out.jpg").is_err());
+        assert!(format("out").is_err());
+
+        // An explicit format wins, and stdout defaults to PDF.
+        let pdf = Output::Path(PathBuf::from("out.pdf"));
+        assert_eq!(
+            output_format(&pdf, Some(OutputFormat::Png)),
+            Ok(OutputFormat::Png)
+        );
+        assert_eq!(output_format(&Output::Stdout, None), Ok(OutputFormat::Pdf));
+    }
+
+    #[test]
+    fn formats_page_templates() {
+        assert!(output_template::has_indexable_template("out-{p}.png"));
+        assert!(output_template::has_indexable_template("out-{0p}.png"));
+        assert!(!output_template::has_indexable_template("out-{t}.png"));
+        assert_eq!(output_template::format("out-{p}.png", 3, 12), "out-3.png");
+        assert_eq!(output_template::format("out-{0p}.png", 3, 12), "out-03.png");
+        assert_eq!(
+            output_template::format("{p}-of-{t}.svg", 3, 12),
+            "3-of-12.svg"
+        );
+    }
+}
@@ -0,0 +1,32 @@
+use typst::diag::StrResult;
+use typst::text::FontVariant;
+use typst_kit::fonts::Fonts;
+
+use crate::args::FontsCommand;
+use crate::config::SimulationConfig;
+
+/// Execute a font listing command.
+pub fn fonts(config: &SimulationConfig, command: FontsCommand) -> StrResult<()> {
+    let mut font_args = config.world_config.font.clone();
+    font_args.merge(command.font);
+
+    let fonts = Fonts::searcher()
+        .include_system_fonts(!font_args.ignore_system_fonts)
+        .search_with(&font_args.font_paths);
+
+    for (name, infos) in fonts.book.families() {
+        println!("{name}");
+        if command.variants {
+            for info in infos {
+                let FontVariant {
+                    style,
+                    weight,
+                    stretch,
+                } = info.variant;
+                println!("- Style: {style:?}, Weight: {weight:?}, Stretch: {stretch:?}");
+            }
+        }
+    }
+
+    Ok(())
+}
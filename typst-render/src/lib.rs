@@ -0,0 +1,107 @@
+//! Renders Typst documents into shared textures and files.
+//!
+//! The `typst-render` binary is a thin wrapper around [`run`]. Applications
+//! embedding the renderer can also drive a [`SystemWorld`] themselves, e.g. to
+//! provide virtual files.
+
+mod animate;
+mod args;
+mod atlas;
+mod compile;
+mod config;
+mod download;
+mod element;
+mod export;
+mod fonts;
+mod live;
+mod package;
+mod present;
+mod query;
+mod render;
+mod sink;
+#[cfg(target_os = "macos")]
+mod syphilm;
+mod timings;
+mod vendor;
+mod watch;
+mod world;
+
+use args::{Command, CompileArgs};
+use config::ConfigSource;
+use typst::diag::StrResult;
+
+pub use args::{CliArguments, Input, ProcessArgs, WorldArgs};
+pub use download::{DownloadEvent, DownloadProgress};
+pub use world::{SystemWorld, WorldCreationError};
+
+/// Execute the requested command.
+pub fn run(args: CliArguments) -> StrResult<()> {
+    let path = config::resolve_path(args.config.as_deref());
+
+    match args.command {
+        Some(Command::Compile(command)) => {
+            let source = ConfigSource {
+                path,
+                args: Some(command.args),
+            };
+            compile::compile(&source.load()?)
+        }
+        Some(Command::Watch(command)) => {
+            let source = ConfigSource {
+                path,
+                args: Some(command.args),
+            };
+            watch::watch(source.load()?, source)
+        }
+        Some(Command::Animate(command)) => {
+            let source = ConfigSource {
+                path,
+                args: Some(command.args),
+            };
+            animate::animate(&source.load()?)
+        }
+        Some(Command::Query(command)) => {
+            let args = CompileArgs {
+                input: command.input.clone(),
+                world: command.world.clone(),
+                process: command.process.clone(),
+                ..CompileArgs::default()
+            };
+            let source = ConfigSource {
+                path,
+                args: Some(args),
+            };
+            query::query(&source.load()?, &command)
+        }
+        Some(Command::Vendor(command)) => {
+            let args = CompileArgs {
+                input: command.input.clone(),
+                world: command.world.clone(),
+                process: command.process.clone(),
+                ..CompileArgs::default()
+            };
+            let source = ConfigSource {
+                path,
+                args: Some(args),
+            };
+            vendor::vendor(&source.load()?, &command)
+        }
+        Some(Command::Fonts(command)) => {
+            fonts::fonts(&config::load_config(path.as_deref())?, command)
+        }
+        // Without a command, the config file decides whether to export files
+        // or to publish live.
+        None => {
+            let source = ConfigSource {
+                path,
+                args: Some(CompileArgs::from_env()?),
+            };
+            let config = source.load()?;
+            if config.output.is_some() {
+                compile::compile(&config)
+            } else {
+                watch::watch(config, source)
+            }
+        }
+    }
+}
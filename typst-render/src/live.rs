@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::net::UdpSocket;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use ecow::eco_format;
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+use tungstenite::Message;
+use typst::diag::StrResult;
+use typst::foundations::Value;
+
+use crate::args::LiveArgs;
+use crate::watch::Signal;
+
+/// How long to wait before connecting to the websocket again.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Starts receiving live inputs as configured and sends the ones that changed
+/// to `tx`. Does nothing if no source is configured.
+pub fn subscribe(args: &LiveArgs, tx: Sender<Signal>) -> StrResult<()> {
+    if let Some(url) = &args.websocket {
+        let url = url.clone();
+        let mut inputs = LiveInputs::new(args.channels.clone(), tx.clone());
+        thread::spawn(move || loop {
+            if let Err(err) = receive_websocket(&url, &mut inputs) {
+                eprintln!("lost live inputs from {url} ({err}), reconnecting");
+            }
+            thread::sleep(RECONNECT_INTERVAL);
+        });
+    }
+
+    if let Some(addr) = args.udp {
+        let socket = UdpSocket::bind(addr)
+            .map_err(|err| eco_format!("failed to listen for live inputs on {addr} ({err})"))?;
+        let mut inputs = LiveInputs::new(args.channels.clone(), tx);
+        thread::spawn(move || {
+            let mut buf = [0; 65536];
+            loop {
+                let Ok((amt, _)) = socket.recv_from(&mut buf) else {
+                    continue;
+                };
+                if !inputs.receive(&String::from_utf8_lossy(&buf[..amt])) {
+                    break;
+                }
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Receives messages from a websocket until the connection fails.
+fn receive_websocket(url: &str, inputs: &mut LiveInputs) -> tungstenite::Result<()> {
+    let (mut socket, _) = tungstenite::connect(url)?;
+    loop {
+        let message = match socket.read()? {
+            Message::Text(text) => text.as_str().to_owned(),
+            Message::Binary(data) => String::from_utf8_lossy(&data).into_owned(),
+            Message::Close(_) => return Ok(()),
+            _ => continue,
+        };
+        if !inputs.receive(&message) {
+            return Ok(());
+        }
+    }
+}
+
+/// Turns messages into inputs and forwards the ones that changed.
+struct LiveInputs {
+    /// The channels to use, or all if empty.
+    channels: Vec<String>,
+    /// The last value that was forwarded for each channel.
+    last: HashMap<String, Value>,
+    /// Receives the inputs that changed.
+    tx: Sender<Signal>,
+}
+
+impl LiveInputs {
+    fn new(channels: Vec<String>, tx: Sender<Signal>) -> Self {
+        Self {
+            channels,
+            last: HashMap::new(),
+            tx,
+        }
+    }
+
+    /// Handles a message. Returns `false` once nobody listens anymore.
+    fn receive(&mut self, message: &str) -> bool {
+        let Ok(JsonValue::Object(values)) = serde_json::from_str(message) else {
+            eprintln!("ignoring live message that isn't a JSON object: {message:?}");
+            return true;
+        };
+
+        let changed: Vec<_> = values
+            .into_iter()
+            .filter(|(key, _)| self.channels.is_empty() || self.channels.contains(key))
+            .filter_map(|(key, value)| Some((key, to_input(value)?)))
+            .filter(|(key, value)| self.last.get(key) != Some(value))
+            .collect();
+
+        if changed.is_empty() {
+            return true;
+        }
+
+        self.last.extend(changed.iter().cloned());
+        self.tx.send(Signal::Inputs(changed)).is_ok()
+    }
+}
+
+/// Converts a channel value to an input the way typed `sys.inputs` are
+/// converted, so that e.g. arrays of samples stay arrays. Channels without a
+/// value are skipped.
+fn to_input(value: JsonValue) -> Option<Value> {
+    match value {
+        JsonValue::Null => None,
+        value => Value::deserialize(value).ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use typst::foundations::{Array, IntoValue};
+
+    use super::*;
+
+    #[test]
+    fn converts_channel_values() {
+        assert_eq!(to_input(json!(null)), None);
+        assert_eq!(to_input(json!(3)), Some(3.into_value()));
+        assert_eq!(
+            to_input(json!([0.5, 1.5])),
+            Some(Array::from_iter([0.5.into_value(), 1.5.into_value()]).into_value())
+        );
+    }
+}
@@ -1,27 +1,15 @@
-mod args;
-mod config;
-mod download;
-mod package;
-mod render;
-mod world;
-
-use objc2_app_kit::NSApplication;
-
-use objc2::{msg_send_id, rc::Id};
-use objc2_foundation::NSData;
-use render::{render_document, Syphilm};
-
-fn main() {
-    let image = render_document();
-    let image_data = NSData::with_bytes(
-        image
-            .encode_png()
-            .expect("Couldn't encode output to png")
-            .as_ref(),
-    );
-
-    let syphilm = Syphilm::init_with_data(&image_data);
-    let app: Id<NSApplication> = unsafe { msg_send_id![&syphilm, app] };
-
-    unsafe { app.run() };
+use std::process::ExitCode;
+
+use clap::Parser;
+use color_print::ceprintln;
+use typst_render_lib::CliArguments;
+
+fn main() -> ExitCode {
+    match typst_render_lib::run(CliArguments::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            ceprintln!("<red,bold>error</>: {}", err);
+            ExitCode::FAILURE
+        }
+    }
 }
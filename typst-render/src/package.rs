@@ -1,13 +1,403 @@
-use typst_kit::package::PackageStorage;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use ecow::eco_format;
+use typst::diag::{PackageError, PackageResult};
+use typst::syntax::package::PackageSpec;
+use typst_kit::package::{DEFAULT_NAMESPACE, DEFAULT_PACKAGES_SUBDIR, DEFAULT_REGISTRY};
 
 use crate::args::PackageArgs;
-use crate::download;
-
-/// Returns a new package storage for the given args.
-pub fn storage(args: &PackageArgs) -> PackageStorage {
-    PackageStorage::new(
-        args.package_cache_path.clone(),
-        args.package_path.clone(),
-        download::downloader(),
-    )
+use crate::download::{self, DownloadListener, Downloader};
+
+/// The project-local directory that `vendor` copies packages to. It's searched
+/// before the package path.
+pub const VENDOR_DIR: &str = "vendor";
+
+/// Package storage that reports the progress of downloads to a listener.
+pub struct Packages {
+    /// The directories with local packages, in the order they are searched.
+    package_paths: Vec<PathBuf>,
+    /// The directory downloaded packages are stored in, if any.
+    package_cache_path: Option<PathBuf>,
+    /// Receives the events of package downloads.
+    listener: DownloadListener,
+    /// Whether packages must not be downloaded.
+    offline: bool,
+    /// The alternate registry to download packages from, if any.
+    registry: Option<String>,
+    /// Downloads packages.
+    downloader: Downloader,
+}
+
+impl Packages {
+    /// Returns a new package storage for the given args that prints download
+    /// progress.
+    ///
+    /// The vendor directory in the project root is searched first if it
+    /// exists, then the configured or default package path.
+    pub fn new(args: &PackageArgs, root: &Path) -> Self {
+        let vendored = Some(root.join(VENDOR_DIR)).filter(|dir| dir.is_dir());
+        let package_path = args
+            .package_path
+            .clone()
+            .or_else(|| dirs::data_dir().map(|dir| dir.join(DEFAULT_PACKAGES_SUBDIR)));
+        let package_cache_path = args
+            .package_cache_path
+            .clone()
+            .or_else(|| dirs::cache_dir().map(|dir| dir.join(DEFAULT_PACKAGES_SUBDIR)));
+
+        Self {
+            package_paths: vendored.into_iter().chain(package_path).collect(),
+            package_cache_path,
+            listener: download::print_events(),
+            offline: args.offline,
+            registry: args.registry.clone(),
+            downloader: Downloader::new(args),
+        }
+    }
+
+    /// Replaces the listener that receives the events of package downloads.
+    pub fn set_listener(&mut self, listener: DownloadListener) {
+        self.listener = listener;
+    }
+
+    /// Makes a package available on disk and returns its directory,
+    /// downloading it if necessary.
+    pub fn prepare(&self, spec: &PackageSpec) -> PackageResult<PathBuf> {
+        if let Some(dir) = self.find(spec) {
+            return Ok(dir);
+        }
+
+        if self.offline {
+            return Err(PackageError::Other(Some(eco_format!(
+                "{spec} is not available offline, add it to the package path or \
+                 run `typst-render vendor` while online"
+            ))));
+        }
+
+        match &self.registry {
+            Some(registry) => self.download(registry, spec),
+            // The default registry only serves the preview namespace.
+            None if spec.namespace == DEFAULT_NAMESPACE => self.download(DEFAULT_REGISTRY, spec),
+            None => Err(PackageError::NotFound(spec.clone())),
+        }
+    }
+
+    /// Downloads a package from a registry and unpacks it into the package
+    /// cache.
+    fn download(&self, registry: &str, spec: &PackageSpec) -> PackageResult<PathBuf> {
+        let Some(cache) = &self.package_cache_path else {
+            return Err(PackageError::Other(Some(
+                "no package cache path to download into".into(),
+            )));
+        };
+
+        let url = format!(
+            "{}/{}/{}-{}.tar.gz",
+            registry.trim_end_matches('/'),
+            spec.namespace,
+            spec.name,
+            spec.version,
+        );
+        let data = self
+            .downloader
+            .download(&url, spec.to_string(), &self.listener)
+            .map_err(|err| PackageError::NetworkFailed(Some(eco_format!("{url}: {err}"))))?;
+
+        // Unpack next to the final directory and move the package into place
+        // once it's complete, so that an interrupted download doesn't leave a
+        // partial package behind that looks installed.
+        let dir = cache.join(subdir(spec));
+        let partial = dir.with_file_name(format!(".{}-{}.partial", spec.version, process::id()));
+        fs::remove_dir_all(&partial).ok();
+
+        let decompressed = flate2::read::GzDecoder::new(data.as_slice());
+        tar::Archive::new(decompressed)
+            .unpack(&partial)
+            .map_err(|err| {
+                fs::remove_dir_all(&partial).ok();
+                PackageError::MalformedArchive(Some(eco_format!("{err}")))
+            })?;
+
+        if let Err(err) = fs::rename(&partial, &dir) {
+            fs::remove_dir_all(&partial).ok();
+            // Another process may have installed the package in the meantime.
+            if !dir.exists() {
+                return Err(PackageError::Other(Some(eco_format!(
+                    "failed to move {spec} into the package cache ({err})"
+                ))));
+            }
+        }
+
+        Ok(dir)
+    }
+
+    /// Looks for a package in the package paths and cache without downloading
+    /// it.
+    fn find(&self, spec: &PackageSpec) -> Option<PathBuf> {
+        self.package_paths
+            .iter()
+            .chain(&self.package_cache_path)
+            .map(|dir| dir.join(subdir(spec)))
+            .find(|dir| dir.exists())
+    }
+}
+
+/// The directory of a package relative to the package path or cache.
+fn subdir(spec: &PackageSpec) -> String {
+    format!("{}/{}/{}", spec.namespace, spec.name, spec.version)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    use clap::Parser;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use parking_lot::Mutex;
+
+    use super::*;
+    use crate::download::DownloadEvent;
+
+    #[test]
+    fn downloads_from_registry() {
+        let temp = temp_dir("registry");
+        let (url, requests) = serve(package_archive());
+
+        // Download straight from the stand-in registry.
+        let args = PackageArgs {
+            package_cache_path: Some(temp.join("cache")),
+            registry: Some(url.clone()),
+            ..PackageArgs::default()
+        };
+        let spec: PackageSpec = "@preview/example:0.1.0".parse().unwrap();
+        let dir = Packages::new(&args, &temp).prepare(&spec).unwrap();
+        assert_eq!(dir, temp.join("cache/preview/example/0.1.0"));
+        assert!(dir.join("lib.typ").is_file());
+        let siblings = fs::read_dir(dir.parent().unwrap()).unwrap().count();
+        assert_eq!(siblings, 1, "the partial download wasn't moved into place");
+        assert_eq!(
+            requests.lock()[0],
+            "GET /preview/example-0.1.0.tar.gz HTTP/1.1"
+        );
+
+        fs::remove_dir_all(temp).ok();
+    }
+
+    #[test]
+    fn downloads_through_proxy() {
+        #[derive(Parser)]
+        struct Cli {
+            #[clap(flatten)]
+            package: PackageArgs,
+        }
+
+        let temp = temp_dir("proxy");
+        let (url, requests) = serve(package_archive());
+
+        // The registry doesn't resolve, so the download only works through
+        // the proxy passed on the command line.
+        let cache = temp.join("cache");
+        let cli = Cli::parse_from([
+            OsStr::new("typst-render"),
+            OsStr::new("--proxy"),
+            OsStr::new(&url),
+            OsStr::new("--package-registry"),
+            OsStr::new("http://registry.invalid"),
+            OsStr::new("--package-cache-path"),
+            cache.as_os_str(),
+        ]);
+        let spec: PackageSpec = "@preview/example:0.2.0".parse().unwrap();
+        Packages::new(&cli.package, &temp).prepare(&spec).unwrap();
+        assert!(cache.join("preview/example/0.2.0/typst.toml").is_file());
+        assert_eq!(
+            requests.lock()[0],
+            "GET http://registry.invalid/preview/example-0.2.0.tar.gz HTTP/1.1"
+        );
+
+        fs::remove_dir_all(temp).ok();
+    }
+
+    #[test]
+    fn reports_download_events_to_listener() {
+        let temp = temp_dir("events");
+        let archive = package_archive();
+        let size = archive.len();
+        let (url, _) = serve(archive);
+
+        let args = PackageArgs {
+            package_cache_path: Some(temp.join("cache")),
+            registry: Some(url),
+            ..PackageArgs::default()
+        };
+        let mut packages = Packages::new(&args, &temp);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&events);
+        packages.set_listener(Arc::new(move |event: &DownloadEvent| {
+            log.lock().push(event.clone())
+        }));
+
+        let spec: PackageSpec = "@preview/example:0.1.0".parse().unwrap();
+        packages.prepare(&spec).unwrap();
+
+        let events = events.lock();
+        assert!(matches!(
+            events.first(),
+            Some(DownloadEvent::Start { package }) if package == "@preview/example:0.1.0"
+        ));
+        assert!(matches!(
+            events.last(),
+            Some(DownloadEvent::Finish(progress)) if progress.downloaded == size
+        ));
+
+        fs::remove_dir_all(temp).ok();
+    }
+
+    #[test]
+    fn fails_without_certificate() {
+        let temp = temp_dir("cert");
+        let (url, requests) = serve(package_archive());
+
+        let args = PackageArgs {
+            package_cache_path: Some(temp.join("cache")),
+            cert: Some(temp.join("missing.pem")),
+            registry: Some(url),
+            ..PackageArgs::default()
+        };
+        let spec: PackageSpec = "@preview/example:0.1.0".parse().unwrap();
+        let result = Packages::new(&args, &temp).prepare(&spec);
+        assert!(matches!(result, Err(PackageError::NetworkFailed(_))));
+        assert!(requests.lock().is_empty());
+
+        fs::remove_dir_all(temp).ok();
+    }
+
+    #[test]
+    fn fails_fast_offline() {
+        let temp = temp_dir("offline");
+        let args = PackageArgs {
+            package_cache_path: Some(temp.join("cache")),
+            offline: true,
+            ..PackageArgs::default()
+        };
+        let spec: PackageSpec = "@preview/example:0.1.0".parse().unwrap();
+        let err = Packages::new(&args, &temp).prepare(&spec).unwrap_err();
+        assert!(err.to_string().contains("@preview/example:0.1.0"));
+
+        fs::remove_dir_all(temp).ok();
+    }
+
+    #[test]
+    fn finds_vendored_and_local_packages() {
+        let temp = temp_dir("vendor");
+        let vendored = temp.join(VENDOR_DIR).join("preview/example/0.1.0");
+        let local = temp.join("packages/local/mine/1.0.0");
+        fs::create_dir_all(&vendored).unwrap();
+        fs::create_dir_all(&local).unwrap();
+        fs::create_dir_all(temp.join("packages/preview/example/0.1.0")).unwrap();
+
+        // Vendored packages take precedence, but don't hide the package path.
+        let args = PackageArgs {
+            package_path: Some(temp.join("packages")),
+            package_cache_path: Some(temp.join("cache")),
+            offline: true,
+            ..PackageArgs::default()
+        };
+        let packages = Packages::new(&args, &temp);
+        let spec: PackageSpec = "@preview/example:0.1.0".parse().unwrap();
+        assert_eq!(packages.prepare(&spec).unwrap(), vendored);
+        let spec: PackageSpec = "@local/mine:1.0.0".parse().unwrap();
+        assert_eq!(packages.prepare(&spec).unwrap(), local);
+
+        fs::remove_dir_all(temp).ok();
+    }
+
+    /// Creates an empty directory for a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("typst-render-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Packs a minimal package into a gzipped tarball.
+    fn package_archive() -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        let files = [
+            (
+                "typst.toml",
+                "[package]\nname = \"example\"\nversion = \"0.1.0\"\nentrypoint = \"lib.typ\"\n",
+            ),
+            ("lib.typ", "#let greet(name) = [Hello, #name!]\n"),
+        ];
+        for (path, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    /// Starts a stand-in server that answers every request with the archive
+    /// and also accepts `CONNECT` like a proxy. Returns its URL and the
+    /// request lines it received.
+    fn serve(archive: Vec<u8>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let log = Arc::clone(&requests);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { continue };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                while let Some(line) = read_head(&mut reader) {
+                    log.lock().push(line.clone());
+                    if line.starts_with("CONNECT") {
+                        stream
+                            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                            .unwrap();
+                        continue;
+                    }
+
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        archive.len()
+                    )
+                    .unwrap();
+                    stream.write_all(&archive).unwrap();
+                    break;
+                }
+            }
+        });
+
+        (url, requests)
+    }
+
+    /// Reads the head of a request and returns its request line.
+    fn read_head(reader: &mut impl BufRead) -> Option<String> {
+        let mut request_line = String::new();
+        reader
+            .read_line(&mut request_line)
+            .ok()
+            .filter(|&n| n > 0)?;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).ok()? == 0 || line.trim().is_empty() {
+                break;
+            }
+        }
+        Some(request_line.trim_end().to_string())
+    }
 }
@@ -0,0 +1,407 @@
+use std::net::UdpSocket;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use color_print::ceprintln;
+use ecow::{eco_format, EcoString};
+use frame_sink::FrameSink;
+use parking_lot::Mutex;
+use typst::diag::StrResult;
+
+use crate::args::{PageMode, PresentArgs, SinkKind};
+use crate::compile::write_make_deps;
+use crate::config::{ConfigSource, SimulationConfig};
+use crate::render::{RenderStats, RenderedPage, Renderer};
+use crate::sink::{to_frame, BoxedSink, Sinks};
+use crate::timings::Timer;
+use crate::watch::watch_world;
+use crate::world::SystemWorld;
+
+/// Publishes the rendered pages to the configured frame sinks and keeps them
+/// up to date.
+///
+/// Syphon outputs need the application to run on the current thread, which
+/// must be the main thread, so the document is recompiled in the background
+/// for them. The page mode, sinks and controls are set up once, so changes to
+/// them in the config file need a restart. Pages that didn't change are
+/// neither rendered nor published again.
+pub fn present(
+    mut world: SystemWorld,
+    mut config: SimulationConfig,
+    source: ConfigSource,
+) -> StrResult<()> {
+    // Start out with an empty frame if the document doesn't compile yet. The
+    // watcher publishes the first good frames once the errors are fixed.
+    let mut timer = Timer::new(&config.process_config);
+    let mut renderer = Renderer::default();
+    let frames = match timer.record(&mut world, |world| renderer.render(world, &config)) {
+        Ok(frames) => {
+            if let Err(err) = write_make_deps(&mut world, &config) {
+                eprintln!("{err}");
+            }
+            frames
+        }
+        Err(err) => {
+            eprintln!("{err}");
+            vec![RenderedPage::blank()]
+        }
+    };
+
+    let mode = config.present_config.mode.unwrap_or_default();
+    let sinks = Sinks::new(&config.present_config);
+    let presenter = Presenter::new(mode, sinks.clone(), frames)?;
+    let presenter = Arc::new(Mutex::new(presenter));
+    spawn_controls(&presenter, &config.present_config);
+
+    let mut watch = move || {
+        watch_world(
+            &mut world,
+            &mut config,
+            &source,
+            &mut timer,
+            |world, config| {
+                let frames = renderer.render(world, config)?;
+                let mut presenter = presenter.lock();
+                presenter.set_frames(frames);
+                log_frames(renderer.stats(), presenter.stats());
+                drop(presenter);
+
+                // The frames are published already, so a failure to write
+                // the dependencies doesn't fail the compilation.
+                if let Err(err) = write_make_deps(world, config) {
+                    eprintln!("{err}");
+                }
+                Ok(())
+            },
+        )
+    };
+
+    if sinks.kind() != SinkKind::Syphon {
+        return watch();
+    }
+
+    // Keep recompiling in the background while the application runs.
+    thread::spawn(move || {
+        if let Err(err) = watch() {
+            eprintln!("stopped watching: {err}");
+        }
+    });
+
+    run_application()
+}
+
+#[cfg(target_os = "macos")]
+fn run_application() -> StrResult<()> {
+    crate::syphilm::run_application()
+}
+
+#[cfg(not(target_os = "macos"))]
+fn run_application() -> StrResult<()> {
+    Err("Syphon outputs are only available on macOS".into())
+}
+
+/// Exposes rendered pages through frame sinks.
+///
+/// In [`PageMode::Sequence`] all pages share one output that shows the
+/// current page. In [`PageMode::Separate`] every page has its own output.
+pub struct Presenter {
+    /// How the pages are exposed.
+    mode: PageMode,
+    /// Opens outputs for pages that are added later on.
+    sinks: Sinks,
+    /// One output in sequence mode, one per page otherwise.
+    outputs: Vec<BoxedSink>,
+    /// The hash of the page each output shows.
+    shown: Vec<u128>,
+    /// The most recently rendered pages.
+    frames: Vec<RenderedPage>,
+    /// The index of the page shown in sequence mode.
+    current: usize,
+    /// How many frames were published and skipped so far.
+    stats: PublishStats,
+}
+
+/// Counts the frames a [`Presenter`] published and skipped.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct PublishStats {
+    /// The number of frames that were published.
+    pub published: usize,
+    /// The number of frames that weren't published because the output
+    /// already showed them.
+    pub skipped: usize,
+}
+
+impl Presenter {
+    /// Creates the outputs for the initial frames.
+    pub fn new(mode: PageMode, sinks: Sinks, frames: Vec<RenderedPage>) -> StrResult<Self> {
+        let shown: Vec<_> = match mode {
+            PageMode::Sequence => vec![frames[0].hash],
+            PageMode::Separate => frames.iter().map(|frame| frame.hash).collect(),
+        };
+        let outputs = match mode {
+            PageMode::Sequence => vec![sinks.open(None, &frames[0].pixmap)?],
+            PageMode::Separate => frames
+                .iter()
+                .enumerate()
+                .map(|(i, frame)| sinks.open(Some(i + 1), &frame.pixmap))
+                .collect::<StrResult<_>>()?,
+        };
+
+        Ok(Self {
+            mode,
+            sinks,
+            stats: PublishStats {
+                published: outputs.len(),
+                skipped: 0,
+            },
+            outputs,
+            shown,
+            frames,
+            current: 0,
+        })
+    }
+
+    /// Replaces the rendered pages and republishes the ones that changed.
+    pub fn set_frames(&mut self, frames: Vec<RenderedPage>) {
+        self.frames = frames;
+        match self.mode {
+            PageMode::Sequence => {
+                self.current = self.current.min(self.frames.len().saturating_sub(1));
+                self.publish_current();
+            }
+            PageMode::Separate => {
+                // Pages that were added since the last compilation get their
+                // own output.
+                for i in self.outputs.len()..self.frames.len() {
+                    let frame = &self.frames[i];
+                    match self.sinks.open(Some(i + 1), &frame.pixmap) {
+                        Ok(output) => {
+                            self.outputs.push(output);
+                            self.shown.push(frame.hash);
+                            self.stats.published += 1;
+                        }
+                        Err(err) => {
+                            eprintln!("{err}");
+                            break;
+                        }
+                    }
+                }
+                for i in 0..self.outputs.len().min(self.frames.len()) {
+                    self.publish(i, i);
+                }
+            }
+        }
+    }
+
+    /// How many frames were published and skipped so far.
+    pub fn stats(&self) -> PublishStats {
+        self.stats
+    }
+
+    /// Moves a page sequence according to a control message.
+    pub fn control(&mut self, control: Control) {
+        if self.mode != PageMode::Sequence || self.frames.is_empty() {
+            return;
+        }
+
+        let count = self.frames.len();
+        self.current = match control {
+            Control::Next => (self.current + 1) % count,
+            Control::Previous => (self.current + count - 1) % count,
+            Control::Page(number) => (number.get() - 1).min(count - 1),
+        };
+
+        self.publish_current();
+    }
+
+    /// Publishes the current page of a sequence.
+    fn publish_current(&mut self) {
+        if self.current < self.frames.len() {
+            self.publish(0, self.current);
+        }
+    }
+
+    /// Publishes a rendered page to an output unless the output already
+    /// shows it. Failures are logged so that the other outputs keep being
+    /// updated.
+    fn publish(&mut self, output: usize, page: usize) {
+        let frame = &self.frames[page];
+        if self.shown[output] == frame.hash {
+            self.stats.skipped += 1;
+            return;
+        }
+
+        if let Err(err) = self.outputs[output].publish(&to_frame(&frame.pixmap)) {
+            eprintln!("failed to publish frame ({err})");
+            return;
+        }
+
+        self.shown[output] = frame.hash;
+        self.stats.published += 1;
+    }
+}
+
+/// Logs how many pages were rendered and published so far, and how many were
+/// skipped because they didn't change.
+fn log_frames(render: RenderStats, publish: PublishStats) {
+    ceprintln!(
+        "<dim>frames: {} rendered, {} unchanged; {} published, {} skipped</>",
+        render.rendered,
+        render.skipped,
+        publish.published,
+        publish.skipped,
+    );
+}
+
+/// Starts the threads that advance a page sequence on a timer or through
+/// control messages, as configured.
+pub fn spawn_controls(presenter: &Arc<Mutex<Presenter>>, args: &PresentArgs) {
+    if let Some(interval) = args.interval {
+        let presenter = Arc::clone(presenter);
+        let interval = Duration::from_secs_f64(interval);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            presenter.lock().control(Control::Next);
+        });
+    }
+
+    if let Some(addr) = args.control {
+        let socket = match UdpSocket::bind(addr) {
+            Ok(socket) => socket,
+            Err(err) => {
+                eprintln!("failed to listen for control messages on {addr} ({err})");
+                return;
+            }
+        };
+
+        let presenter = Arc::clone(presenter);
+        thread::spawn(move || {
+            let mut buf = [0; 1024];
+            loop {
+                let Ok((amt, _)) = socket.recv_from(&mut buf) else {
+                    continue;
+                };
+
+                let message = String::from_utf8_lossy(&buf[..amt]);
+                match message.parse() {
+                    Ok(control) => presenter.lock().control(control),
+                    Err(err) => eprintln!("ignoring control message {message:?} ({err})"),
+                }
+            }
+        });
+    }
+}
+
+/// A message that moves a page sequence.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Control {
+    /// Show the next page, wrapping around at the end.
+    Next,
+    /// Show the previous page, wrapping around at the start.
+    Previous,
+    /// Show the page with the given number within the sequence.
+    Page(NonZeroUsize),
+}
+
+impl FromStr for Control {
+    type Err = EcoString;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["next"] => Ok(Control::Next),
+            ["previous" | "prev"] => Ok(Control::Previous),
+            ["page", number] => number
+                .parse()
+                .map(Control::Page)
+                .map_err(|_| eco_format!("{number} is not a valid page number")),
+            _ => Err("expected `next`, `previous` or `page <n>`".into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use tiny_skia::Pixmap;
+
+    use super::*;
+
+    #[test]
+    fn parses_control_messages() {
+        assert_eq!("next".parse(), Ok(Control::Next));
+        assert_eq!(" prev\n".parse(), Ok(Control::Previous));
+        assert_eq!(
+            "page 3".parse(),
+            Ok(Control::Page(NonZeroUsize::new(3).unwrap()))
+        );
+        for message in ["page 0", "page", "jump"] {
+            assert!(
+                message.parse::<Control>().is_err(),
+                "{message:?} was accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn cycles_through_sequence() {
+        let temp = temp_dir("sequence");
+        let mut presenter = presenter(&temp, PageMode::Sequence, 3);
+        let mut current = |control| {
+            presenter.control(control);
+            presenter.current
+        };
+
+        assert_eq!(current(Control::Next), 1);
+        assert_eq!(current(Control::Next), 2);
+        assert_eq!(current(Control::Next), 0);
+        assert_eq!(current(Control::Previous), 2);
+        assert_eq!(current(Control::Page(NonZeroUsize::new(9).unwrap())), 2);
+        assert_eq!(current(Control::Page(NonZeroUsize::new(1).unwrap())), 0);
+
+        // Jumping to the page that's already shown doesn't publish it again.
+        let stats = presenter.stats();
+        assert_eq!((stats.published, stats.skipped), (6, 1));
+
+        fs::remove_dir_all(temp).ok();
+    }
+
+    #[test]
+    fn ignores_controls_for_separate_pages() {
+        let temp = temp_dir("separate");
+        let mut presenter = presenter(&temp, PageMode::Separate, 2);
+        presenter.control(Control::Next);
+        assert_eq!(presenter.current, 0);
+        assert_eq!(presenter.stats().published, 2);
+
+        fs::remove_dir_all(temp).ok();
+    }
+
+    /// Creates a presenter that writes PNG files for distinct pages.
+    fn presenter(dir: &Path, mode: PageMode, pages: u128) -> Presenter {
+        let args = PresentArgs {
+            sink: Some(SinkKind::Png),
+            sink_target: Some(dir.to_string_lossy().into()),
+            ..PresentArgs::default()
+        };
+        let frames = (0..pages)
+            .map(|hash| RenderedPage {
+                hash,
+                pixmap: Arc::new(Pixmap::new(2, 2).unwrap()),
+            })
+            .collect();
+        Presenter::new(mode, Sinks::new(&args), frames).unwrap()
+    }
+
+    /// Creates an empty directory for a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("typst-render-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+}
@@ -0,0 +1,151 @@
+use comemo::Track;
+use ecow::{eco_format, EcoString};
+use serde::Serialize;
+use typst::diag::{bail, StrResult};
+use typst::engine::Sink;
+use typst::foundations::{Content, IntoValue, LocatableSelector, Scope};
+use typst::layout::PagedDocument;
+use typst::syntax::Span;
+use typst::World;
+use typst_eval::{eval_string, EvalMode};
+
+use crate::args::{QueryCommand, SerializationFormat};
+use crate::compile::compile_document;
+use crate::config::SimulationConfig;
+use crate::timings::Timer;
+use crate::world::SystemWorld;
+
+/// Execute a query command.
+pub fn query(config: &SimulationConfig, command: &QueryCommand) -> StrResult<()> {
+    // Create the world that serves sources, files, and fonts.
+    let mut world = SystemWorld::new(&config.input, &config.world_config, &config.process_config)?;
+
+    let mut timer = Timer::new(&config.process_config);
+    let document = timer.record(&mut world, |world| {
+        compile_document::<PagedDocument>(world, config.diagnostic_format())
+    })?;
+    let data = retrieve(&world, command, &document)?;
+    let serialized = format(data, command)?;
+    println!("{serialized}");
+
+    Ok(())
+}
+
+/// Retrieve the matches for the selector.
+fn retrieve(
+    world: &dyn World,
+    command: &QueryCommand,
+    document: &PagedDocument,
+) -> StrResult<Vec<Content>> {
+    let selector = eval_string(
+        &typst::ROUTINES,
+        world.track(),
+        Sink::new().track_mut(),
+        &command.selector,
+        Span::detached(),
+        EvalMode::Code,
+        Scope::default(),
+    )
+    .map_err(|errors| {
+        let mut message = EcoString::from("failed to evaluate selector");
+        for (i, error) in errors.into_iter().enumerate() {
+            message.push_str(if i == 0 { ": " } else { ", " });
+            message.push_str(&error.message);
+        }
+        message
+    })?
+    .cast::<LocatableSelector>()
+    .map_err(|err| eco_format!("failed to cast selector ({})", err.message()))?;
+
+    Ok(document
+        .introspector
+        .query(&selector.0)
+        .into_iter()
+        .collect())
+}
+
+/// Format the query result in the output format.
+fn format(elements: Vec<Content>, command: &QueryCommand) -> StrResult<String> {
+    if command.one && elements.len() != 1 {
+        bail!("expected exactly one element, found {}", elements.len());
+    }
+
+    let mapped: Vec<_> = elements
+        .into_iter()
+        .filter_map(|c| match &command.field {
+            Some(field) => c.get_by_name(field).ok(),
+            _ => Some(c.into_value()),
+        })
+        .collect();
+
+    if command.one {
+        let Some(value) = mapped.first() else {
+            bail!("no such field found for element");
+        };
+        serialize(value, command.format, command.pretty)
+    } else {
+        serialize(&mapped, command.format, command.pretty)
+    }
+}
+
+/// Serialize data to the output format.
+fn serialize(
+    data: &impl Serialize,
+    format: SerializationFormat,
+    pretty: bool,
+) -> StrResult<String> {
+    match format {
+        SerializationFormat::Json => if pretty {
+            serde_json::to_string_pretty(data)
+        } else {
+            serde_json::to_string(data)
+        }
+        .map_err(|err| eco_format!("failed to serialize to JSON ({err})")),
+        SerializationFormat::Yaml => serde_yaml::to_string(data)
+            .map_err(|err| eco_format!("failed to serialize to YAML ({err})")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use typst::foundations::NativeElement;
+    use typst::introspection::MetadataElem;
+
+    use super::*;
+
+    #[test]
+    fn formats_exactly_one_element() {
+        let command = command(&["--one", "--field", "value"]);
+        assert_eq!(format(metadata(&[1]), &command).unwrap(), "1");
+        assert_eq!(
+            format(metadata(&[1, 2]), &command).unwrap_err(),
+            "expected exactly one element, found 2"
+        );
+        assert_eq!(
+            format(metadata(&[]), &command).unwrap_err(),
+            "expected exactly one element, found 0"
+        );
+    }
+
+    #[test]
+    fn formats_all_elements() {
+        let command = command(&["--field", "value"]);
+        assert_eq!(format(metadata(&[1, 2]), &command).unwrap(), "[1,2]");
+        assert_eq!(format(metadata(&[]), &command).unwrap(), "[]");
+    }
+
+    /// Parses a query for metadata with the given flags.
+    fn command(flags: &[&str]) -> QueryCommand {
+        let args = ["query", "metadata"].iter().chain(flags);
+        QueryCommand::try_parse_from(args).unwrap()
+    }
+
+    /// Creates metadata elements with the given values.
+    fn metadata(values: &[i64]) -> Vec<Content> {
+        values
+            .iter()
+            .map(|&value| MetadataElem::new(value.into_value()).pack())
+            .collect()
+    }
+}
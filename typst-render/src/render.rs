@@ -1,41 +1,523 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::Arc;
+
 use ecow::eco_format;
-use objc2::mutability::Mutable;
-use objc2::rc::Id;
-use objc2::{class, extern_class, msg_send_id, ClassType};
-use objc2_foundation::{NSData, NSObject};
-use tiny_skia::Pixmap;
+use palette::{LinSrgb, Srgb};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use tiny_skia::{ColorU8, FilterQuality, Pixmap, PixmapPaint, Transform};
+use typst::diag::{bail, StrResult};
+use typst::foundations::Smart;
+use typst::layout::{Abs, Page, PagedDocument};
+use typst::utils::hash128;
+use typst::visualize::{Color, Paint};
 
-use crate::config;
+use crate::args::{Background, ColorSpace, Fit, RenderArgs};
+use crate::atlas::{self, atlas_pages};
+use crate::compile::compile_document;
+use crate::config::SimulationConfig;
+use crate::element::crop_to_element;
 use crate::world::SystemWorld;
 
-extern_class!(
-    pub struct Syphilm;
+/// The largest number of pixels a rendered image may have, which take 1 GiB
+/// in RGBA.
+const MAX_PIXELS: f32 = (1 << 28) as f32;
+
+/// A rendered page.
+#[derive(Debug, Clone)]
+pub struct RenderedPage {
+    /// A hash of the laid-out page and the settings it was rendered with.
+    /// Equal hashes mean equal pixels.
+    pub hash: u128,
+    /// The rendered pixels.
+    pub pixmap: Arc<Pixmap>,
+}
+
+impl RenderedPage {
+    /// A blank page to show until the document compiles.
+    pub fn blank() -> Self {
+        Self {
+            hash: 0,
+            pixmap: Arc::new(Pixmap::new(1, 1).unwrap()),
+        }
+    }
+}
+
+/// Renders the configured pages of a document.
+///
+/// Pages that look exactly like a page of the previous rendering are not
+/// rasterized again; their pixmap is reused instead. If an atlas is
+/// configured, the pages are packed into a single image.
+#[derive(Debug, Default)]
+pub struct Renderer {
+    /// The pages of the previous rendering by hash.
+    cache: HashMap<u128, Arc<Pixmap>>,
+    /// The previous atlas, if any.
+    atlas: Option<RenderedPage>,
+    /// How many pages were rendered and reused so far.
+    stats: RenderStats,
+}
+
+/// Counts the pages a [`Renderer`] rasterized and reused.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct RenderStats {
+    /// The number of pages that were rasterized.
+    pub rendered: usize,
+    /// The number of pages that were unchanged and not rasterized again.
+    pub skipped: usize,
+}
+
+impl Renderer {
+    /// Compiles the world's main file and renders the configured pages.
+    pub fn render(
+        &mut self,
+        world: &SystemWorld,
+        config: &SimulationConfig,
+    ) -> StrResult<Vec<RenderedPage>> {
+        let document = compile_document::<PagedDocument>(world, config.diagnostic_format())?;
+        self.render_document(&document, config.live_ppi(), config)
+    }
+
+    /// Renders the configured pages of a compiled document at the given
+    /// resolution.
+    pub fn render_document(
+        &mut self,
+        document: &PagedDocument,
+        ppi: f32,
+        config: &SimulationConfig,
+    ) -> StrResult<Vec<RenderedPage>> {
+        // The pages of an atlas are converted to the color space together.
+        let packed = config.atlas_config.enabled();
+        let pages = if packed {
+            atlas_pages(document, config)?
+        } else {
+            selected_pages(document, config)?
+                .into_iter()
+                .map(|(i, page)| (i, page, Cow::Borrowed(&config.render_config)))
+                .collect()
+        };
+
+        let pages: Vec<_> = pages
+            .into_iter()
+            .map(|(i, page, args)| {
+                let settings = (args.background, args.color_space, args.fit, args.align);
+                let size = (ppi.to_bits(), args.width, args.height);
+                (hash128(&(&page, settings, size, packed)), i, page, args)
+            })
+            .collect();
+
+        let cache = &self.cache;
+        let rendered = pages
+            .par_iter()
+            .map(|(hash, _, page, args)| {
+                let pixmap = match cache.get(hash) {
+                    Some(pixmap) => Arc::clone(pixmap),
+                    None if packed => Arc::new(rasterize(page, ppi, args)?),
+                    None => Arc::new(render_page(page, ppi, args)?),
+                };
+                Ok(RenderedPage {
+                    hash: *hash,
+                    pixmap,
+                })
+            })
+            .collect::<StrResult<Vec<_>>>()?;
+
+        let skipped = pages
+            .iter()
+            .filter(|(hash, ..)| cache.contains_key(hash))
+            .count();
+        self.stats.skipped += skipped;
+        self.stats.rendered += pages.len() - skipped;
+
+        self.cache = rendered
+            .iter()
+            .map(|page| (page.hash, Arc::clone(&page.pixmap)))
+            .collect();
+
+        if !packed {
+            return Ok(rendered);
+        }
+
+        let numbers: Vec<_> = pages.iter().map(|(_, i, ..)| i + 1).collect();
+        Ok(vec![self.pack(&numbers, &rendered, config)?])
+    }
+
+    /// Packs rendered pages into an atlas and writes the sidecar, unless
+    /// neither the pages nor the atlas changed.
+    fn pack(
+        &mut self,
+        numbers: &[usize],
+        pages: &[RenderedPage],
+        config: &SimulationConfig,
+    ) -> StrResult<RenderedPage> {
+        let args = &config.render_config;
+        let hashes: Vec<_> = pages.iter().map(|page| page.hash).collect();
+        let hash = hash128(&(
+            hashes,
+            &config.atlas_config,
+            args.background,
+            args.color_space,
+        ));
+        if let Some(atlas) = self.atlas.as_ref().filter(|atlas| atlas.hash == hash) {
+            return Ok(atlas.clone());
+        }
+
+        let layout = atlas::layout(&config.atlas_config, numbers, pages)?;
+        let mut pixmap = atlas::compose(&layout, pages, args.background)?;
+        convert_color_space(&mut pixmap, args.color_space.unwrap_or_default());
+        if let Some(path) = &config.atlas_config.sidecar {
+            atlas::write_sidecar(path, &layout)?;
+        }
+
+        let atlas = RenderedPage {
+            hash,
+            pixmap: Arc::new(pixmap),
+        };
+        self.atlas = Some(atlas.clone());
+        Ok(atlas)
+    }
+
+    /// How many pages were rendered and reused so far.
+    pub fn stats(&self) -> RenderStats {
+        self.stats
+    }
+}
+
+/// Returns the configured pages with their zero-based index, or only the
+/// configured element as a page of its own.
+pub fn selected_pages<'a>(
+    document: &'a PagedDocument,
+    config: &SimulationConfig,
+) -> StrResult<Vec<(usize, Cow<'a, Page>)>> {
+    let args = &config.render_config;
+    if let Some(label) = &args.element {
+        let padding = Abs::pt(args.padding.unwrap_or_default());
+        let page = crop_to_element(document, label, padding)?;
+        return Ok(vec![(0, Cow::Owned(page))]);
+    }
+
+    let pages: Vec<_> = document
+        .pages
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| config.includes_page(*i))
+        .map(|(i, page)| (i, Cow::Borrowed(page)))
+        .collect();
+
+    if pages.is_empty() {
+        bail!("none of the selected pages exist in the document");
+    }
+
+    Ok(pages)
+}
+
+/// Renders a page with the configured size, background and color space.
+///
+/// Without a configured width or height, the size follows from the PPI
+/// (pixels per inch).
+pub fn render_page(page: &Page, ppi: f32, args: &RenderArgs) -> StrResult<Pixmap> {
+    let mut pixmap = rasterize(page, ppi, args)?;
+    convert_color_space(&mut pixmap, args.color_space.unwrap_or_default());
+    Ok(pixmap)
+}
+
+/// Renders a page with the configured size and background, leaving the
+/// pixels in sRGB.
+fn rasterize(page: &Page, ppi: f32, args: &RenderArgs) -> StrResult<Pixmap> {
+    let page = with_background(page, args.background);
+    match target_size(&page, args) {
+        Some((width, height)) => render_fitted(&page, width, height, args),
+        None => render_scaled(&page, ppi / 72.0),
+    }
+}
+
+/// The configured image size in pixels, if any. A missing dimension follows
+/// from the page's aspect ratio.
+fn target_size(page: &Page, args: &RenderArgs) -> Option<(u32, u32)> {
+    let size = page.frame.size();
+    let aspect = size.x.to_pt() / size.y.to_pt();
+    if !(aspect.is_finite() && aspect > 0.0) {
+        return None;
+    }
+
+    let scaled = |length: u32, factor: f64| ((length as f64 * factor).round() as u32).max(1);
+    match (args.width, args.height) {
+        (None, None) => None,
+        (Some(width), Some(height)) => Some((width.get(), height.get())),
+        (Some(width), None) => Some((width.get(), scaled(width.get(), 1.0 / aspect))),
+        (None, Some(height)) => Some((scaled(height.get(), aspect), height.get())),
+    }
+}
+
+/// Renders a page into an image of exactly the given size according to the
+/// fit mode and alignment. Space the page doesn't cover is filled with the
+/// configured background color, or left transparent.
+fn render_fitted(page: &Page, width: u32, height: u32, args: &RenderArgs) -> StrResult<Pixmap> {
+    let size = page.frame.size();
+    let (page_width, page_height) = (size.x.to_pt() as f32, size.y.to_pt() as f32);
+    let sx = width as f32 / page_width;
+    let sy = height as f32 / page_height;
+    let (sx, sy) = match args.fit.unwrap_or_default() {
+        Fit::Contain => (sx.min(sy), sx.min(sy)),
+        Fit::Cover => (sx.max(sy), sx.max(sy)),
+        Fit::Stretch => (sx, sy),
+    };
+
+    let mut canvas = new_pixmap(width, height)?;
+
+    // The background color fills the whole canvas, so the page itself must
+    // not add it again where it's drawn on top.
+    let page = match args.background {
+        Some(Background::Color([r, g, b, a])) => {
+            canvas.fill(tiny_skia::Color::from_rgba8(r, g, b, a));
+            with_background(page, Some(Background::Transparent))
+        }
+        _ => Cow::Borrowed(page),
+    };
+
+    // Render at the larger scale, so that stretching only ever shrinks.
+    let scale = sx.max(sy);
+    let rendered = render_scaled(&page, scale)?;
 
-    unsafe impl ClassType for Syphilm {
-        type Super = NSObject;
-        type Mutability = Mutable;
-        const NAME: &'static str = "Syphilm";
+    let (ax, ay) = args.align.unwrap_or_default().factors();
+    let x = ((width as f32 - page_width * sx) * ax).round();
+    let y = ((height as f32 - page_height * sy) * ay).round();
+    let paint = PixmapPaint {
+        quality: FilterQuality::Bilinear,
+        ..PixmapPaint::default()
+    };
+    let ts = Transform::from_row(sx / scale, 0.0, 0.0, sy / scale, x, y);
+    canvas.draw_pixmap(0, 0, rendered.as_ref(), &paint, ts, None);
+    Ok(canvas)
+}
+
+/// Renders a page at the given scale in pixels per point, failing instead of
+/// panicking in typst-render if the image would be too large.
+fn render_scaled(page: &Page, scale: f32) -> StrResult<Pixmap> {
+    let size = page.frame.size();
+    let pixels = |length: Abs| (scale * length.to_pt() as f32).round().max(1.0);
+    check_size(pixels(size.x), pixels(size.y))?;
+    Ok(typst_render::render(page, scale))
+}
+
+/// Creates an empty image, failing if it's too large.
+pub fn new_pixmap(width: u32, height: u32) -> StrResult<Pixmap> {
+    check_size(width as f32, height as f32)?;
+    Pixmap::new(width, height).ok_or_else(|| eco_format!("failed to create {width}x{height} image"))
+}
+
+/// Fails if an image with the given size in pixels is too large to render.
+fn check_size(width: f32, height: f32) -> StrResult<()> {
+    // Also rejects sizes that aren't finite.
+    if !(width * height <= MAX_PIXELS) {
+        bail!("image of {width}x{height} pixels is too large");
     }
-);
+    Ok(())
+}
+
+/// Replaces the fill of a page with the configured background, if any.
+pub fn with_background(page: &Page, background: Option<Background>) -> Cow<Page> {
+    let fill = match background {
+        None => return Cow::Borrowed(page),
+        Some(Background::Transparent) => None,
+        Some(Background::Color([r, g, b, a])) => Some(Paint::Solid(Color::from_u8(r, g, b, a))),
+    };
+
+    let mut page = page.clone();
+    page.fill = Smart::Custom(fill);
+    Cow::Owned(page)
+}
 
-impl Syphilm {
-    pub fn init_with_data(data: &NSData) -> Id<Self> {
-        let syphilm_class = class!(Syphilm);
-        unsafe { msg_send_id![msg_send_id![syphilm_class, alloc], initWithData :data] }
+/// Converts linear-light sRGB to linear-light Display P3. Both share the D65
+/// white point, so this is a plain change of primaries.
+const SRGB_TO_P3: [[f32; 3]; 3] = [
+    [0.822_462_1, 0.177_538, 0.0],
+    [0.033_194_2, 0.966_805_8, 0.0],
+    [0.017_082_7, 0.072_397_4, 0.910_519_9],
+];
+
+/// Converts the sRGB pixels Typst renders into the given color space.
+///
+/// Pixels are premultiplied, so they are converted with straight alpha and
+/// premultiplied again afterwards. Otherwise the nonlinear transfer
+/// functions would shift the colors of semi-transparent pixels.
+fn convert_color_space(pixmap: &mut Pixmap, space: ColorSpace) {
+    let convert: fn(LinSrgb<f32>) -> (f32, f32, f32) = match space {
+        ColorSpace::Srgb => return,
+        ColorSpace::DisplayP3 => |linear| {
+            let [r, g, b] = SRGB_TO_P3
+                .map(|row| row[0] * linear.red + row[1] * linear.green + row[2] * linear.blue);
+            Srgb::from_linear(LinSrgb::new(r, g, b)).into_components()
+        },
+        ColorSpace::Linear => |linear| linear.into_components(),
+    };
+
+    let quantize = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+    for pixel in pixmap.pixels_mut() {
+        let color = pixel.demultiply();
+        if color.alpha() == 0 {
+            continue;
+        }
+
+        let linear: LinSrgb<f32> = Srgb::new(color.red(), color.green(), color.blue())
+            .into_format::<f32>()
+            .into_linear();
+        let (r, g, b) = convert(linear);
+        *pixel =
+            ColorU8::from_rgba(quantize(r), quantize(g), quantize(b), color.alpha()).premultiply();
     }
 }
 
-pub fn render_document() -> Pixmap {
-    let config = config::load_config("config.toml");
+/// The chromaticities of the sRGB primaries and D65 white point as stored in
+/// a PNG `cHRM` chunk: white, red, green and blue, times 100000.
+const SRGB_CHROMATICITIES: [u32; 8] = [31270, 32900, 64000, 33000, 30000, 60000, 15000, 6000];
+
+/// The chromaticities of the Display P3 primaries and D65 white point.
+const P3_CHROMATICITIES: [u32; 8] = [31270, 32900, 68000, 32000, 26500, 69000, 15000, 6000];
+
+/// Encodes rendered pixels in the given color space as PNG.
+///
+/// Viewers assume sRGB for untagged images, so other color spaces are
+/// tagged with their primaries and transfer function. The sRGB transfer
+/// function of Display P3 is approximated with a gamma of 2.2.
+pub fn encode_png(pixmap: &Pixmap, space: ColorSpace) -> StrResult<Vec<u8>> {
+    let mut png = pixmap
+        .encode_png()
+        .map_err(|err| eco_format!("failed to encode PNG file ({err})"))?;
+
+    let (chromaticities, gamma) = match space {
+        ColorSpace::Srgb => return Ok(png),
+        ColorSpace::DisplayP3 => (P3_CHROMATICITIES, 45455),
+        ColorSpace::Linear => (SRGB_CHROMATICITIES, 100000),
+    };
+
+    let mut chunks = png_chunk(b"gAMA", &u32::to_be_bytes(gamma));
+    let data: Vec<u8> = chromaticities
+        .iter()
+        .flat_map(|v| v.to_be_bytes())
+        .collect();
+    chunks.extend(png_chunk(b"cHRM", &data));
+
+    // The color chunks have to come before the image data, so they go right
+    // after the signature and the fixed-size header chunk.
+    png.splice(8 + 25..8 + 25, chunks);
+    Ok(png)
+}
+
+/// Frames data as a PNG chunk with its length and checksum.
+fn png_chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut crc = flate2::Crc::new();
+    crc.update(kind);
+    crc.update(data);
+
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend((data.len() as u32).to_be_bytes());
+    chunk.extend(kind);
+    chunk.extend(data);
+    chunk.extend(crc.sum().to_be_bytes());
+    chunk
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::args::{FontArgs, Input, ProcessArgs, WorldArgs};
+
+    #[test]
+    fn skips_unchanged_pages() {
+        let config = SimulationConfig::default();
+        let mut renderer = Renderer::default();
+        let stats = |renderer: &Renderer| {
+            let stats = renderer.stats();
+            (stats.rendered, stats.skipped)
+        };
+
+        let first = compile("skip-first", "#rect()\n#pagebreak()\n#circle()");
+        let rendered = renderer.render_document(&first, 72.0, &config).unwrap();
+        assert_eq!(stats(&renderer), (2, 0));
 
-    let ppi = 3.0;
+        // The same pages are reused.
+        let again = renderer.render_document(&first, 72.0, &config).unwrap();
+        assert_eq!(stats(&renderer), (2, 2));
+        assert!(Arc::ptr_eq(&rendered[0].pixmap, &again[0].pixmap));
 
-    let world = SystemWorld::new(&config.input, &config.world_config, &config.process_config)
-        .map_err(|err| eco_format!("{err}"))
+        // Only the page that changed is rendered again.
+        let second = compile("skip-second", "#rect()\n#pagebreak()\n#square()");
+        let changed = renderer.render_document(&second, 72.0, &config).unwrap();
+        assert_eq!(stats(&renderer), (3, 3));
+        assert_eq!(changed[0].hash, rendered[0].hash);
+        assert_ne!(changed[1].hash, rendered[1].hash);
+
+        // A new resolution changes every page.
+        renderer.render_document(&second, 96.0, &config).unwrap();
+        assert_eq!(stats(&renderer), (5, 3));
+    }
+
+    #[test]
+    fn rejects_oversized_images() {
+        assert!(check_size(1920.0, 1080.0).is_ok());
+        assert!(check_size(100_000.0, 100_000.0).is_err());
+        assert!(check_size(f32::INFINITY, 1.0).is_err());
+        assert!(new_pixmap(2, 2).is_ok());
+        assert!(new_pixmap(u32::MAX, u32::MAX).is_err());
+    }
+
+    #[test]
+    fn tags_png_color_space() {
+        let pixmap = Pixmap::new(2, 2).unwrap();
+        let chunks = |png: &[u8]| {
+            let mut kinds = vec![];
+            let mut rest = &png[8..];
+            while !rest.is_empty() {
+                let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+                let (chunk, next) = rest.split_at(len + 12);
+                let mut crc = flate2::Crc::new();
+                crc.update(&chunk[4..len + 8]);
+                assert_eq!(crc.sum().to_be_bytes(), chunk[len + 8..]);
+                kinds.push(String::from_utf8(chunk[4..8].to_vec()).unwrap());
+                rest = next;
+            }
+            kinds
+        };
+
+        let srgb = encode_png(&pixmap, ColorSpace::Srgb).unwrap();
+        assert!(!chunks(&srgb)
+            .iter()
+            .any(|kind| kind == "gAMA" || kind == "cHRM"));
+
+        for space in [ColorSpace::DisplayP3, ColorSpace::Linear] {
+            let png = encode_png(&pixmap, space).unwrap();
+            assert_eq!(chunks(&png)[..3], ["IHDR", "gAMA", "cHRM"]);
+        }
+    }
+
+    /// Compiles a document with small pages.
+    fn compile(name: &str, body: &str) -> PagedDocument {
+        let temp =
+            std::env::temp_dir().join(format!("typst-render-render-{name}-{}", std::process::id()));
+        fs::create_dir_all(&temp).unwrap();
+        let main = temp.join("main.typ");
+        fs::write(
+            &main,
+            format!("#set page(width: 60pt, height: 40pt)\n{body}"),
+        )
         .unwrap();
-    let document = typst::compile(&world).output.unwrap();
 
-    let page = &document.pages[0];
+        let world_args = WorldArgs {
+            root: Some(temp.clone()),
+            font: FontArgs {
+                ignore_system_fonts: true,
+                ..FontArgs::default()
+            },
+            ..WorldArgs::default()
+        };
+        let world =
+            SystemWorld::new(&Input::Path(main), &world_args, &ProcessArgs::default()).unwrap();
+        let document = typst::compile::<PagedDocument>(&world).output.unwrap();
 
-    typst_render::render(page, ppi)
+        fs::remove_dir_all(temp).ok();
+        document
+    }
 }
@@ -0,0 +1,112 @@
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+
+use ecow::eco_format;
+use frame_sink::{Frame, FrameSink, PngDirectory};
+use tiny_skia::Pixmap;
+use typst::diag::StrResult;
+
+use crate::args::{PresentArgs, SinkKind};
+
+/// The directory PNG frames are written to by default.
+const DEFAULT_PNG_DIR: &str = "frames";
+
+/// The number of the newest PNG frames kept in the directory.
+const PNG_FRAMES_KEPT: NonZeroU64 = NonZeroU64::new(16).unwrap();
+
+/// The shared memory name frames are published under by default.
+const DEFAULT_SHARED_MEMORY_NAME: &str = "/typst-render";
+
+/// The number of frames kept in a shared memory ring buffer.
+#[cfg(unix)]
+const SHARED_MEMORY_SLOTS: u32 = 3;
+
+/// A frame sink that can be moved to the thread that recompiles.
+pub type BoxedSink = Box<dyn FrameSink + Send>;
+
+/// Opens the frame sinks that rendered pages are published to.
+#[derive(Debug, Clone)]
+pub struct Sinks {
+    /// Which kind of sink to open.
+    kind: SinkKind,
+    /// The directory or shared memory name, if one was configured.
+    target: Option<String>,
+}
+
+impl Sinks {
+    pub fn new(args: &PresentArgs) -> Self {
+        Self {
+            kind: args.sink.unwrap_or_default(),
+            target: args.sink_target.clone(),
+        }
+    }
+
+    /// Which kind of sink is opened.
+    pub fn kind(&self) -> SinkKind {
+        self.kind
+    }
+
+    /// Opens a sink that starts out showing the given frame.
+    ///
+    /// Pass the page number for an output that only shows that page and
+    /// `None` for one that shows a page sequence.
+    pub fn open(&self, page: Option<usize>, frame: &Pixmap) -> StrResult<BoxedSink> {
+        let mut sink = match self.kind {
+            // Syphilm already starts out showing the frame it's created with.
+            SinkKind::Syphon => return open_syphon(frame),
+            SinkKind::Png => {
+                let dir = PathBuf::from(self.target.as_deref().unwrap_or(DEFAULT_PNG_DIR));
+                let prefix = match page {
+                    Some(number) => format!("page{number}-"),
+                    None => "frame-".into(),
+                };
+                let sink = PngDirectory::new(&dir, prefix).map_err(|err| {
+                    eco_format!("failed to create frame directory {} ({err})", dir.display())
+                })?;
+                Box::new(sink.keep_last(PNG_FRAMES_KEPT))
+            }
+            SinkKind::SharedMemory => {
+                let name = self.target.as_deref().unwrap_or(DEFAULT_SHARED_MEMORY_NAME);
+                let name = match page {
+                    Some(number) => format!("{name}-{number}"),
+                    None => name.into(),
+                };
+                open_shared_memory(&name, frame)?
+            }
+        };
+
+        sink.publish(&to_frame(frame))
+            .map_err(|err| eco_format!("failed to publish frame ({err})"))?;
+
+        Ok(sink)
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn open_syphon(frame: &Pixmap) -> StrResult<BoxedSink> {
+    Ok(Box::new(crate::syphilm::SyphilmSink::new(&to_frame(
+        frame,
+    ))?))
+}
+
+#[cfg(not(target_os = "macos"))]
+fn open_syphon(_: &Pixmap) -> StrResult<BoxedSink> {
+    Err("Syphon outputs are only available on macOS, choose another sink instead".into())
+}
+
+#[cfg(unix)]
+fn open_shared_memory(name: &str, frame: &Pixmap) -> StrResult<BoxedSink> {
+    let sink = frame_sink::SharedMemoryRing::new(name, SHARED_MEMORY_SLOTS, frame.data().len())
+        .map_err(|err| eco_format!("failed to create shared memory {name} ({err})"))?;
+    Ok(Box::new(sink))
+}
+
+#[cfg(not(unix))]
+fn open_shared_memory(_: &str, _: &Pixmap) -> StrResult<BoxedSink> {
+    Err("shared memory outputs are only available on Unix systems".into())
+}
+
+/// Views a rendered page as a frame.
+pub fn to_frame(pixmap: &Pixmap) -> Frame {
+    Frame::new(pixmap.width(), pixmap.height(), pixmap.data())
+}
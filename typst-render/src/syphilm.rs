@@ -0,0 +1,93 @@
+use std::io;
+
+use ecow::eco_format;
+use frame_sink::{Frame, FrameSink};
+use objc2::mutability::Mutable;
+use objc2::rc::Id;
+use objc2::{class, extern_class, extern_methods, msg_send_id, ClassType};
+use objc2_app_kit::NSApplication;
+use objc2_foundation::{MainThreadMarker, NSData, NSObject};
+use tiny_skia::PixmapRef;
+use typst::diag::{bail, StrResult};
+
+extern_class!(
+    pub struct Syphilm;
+
+    unsafe impl ClassType for Syphilm {
+        type Super = NSObject;
+        type Mutability = Mutable;
+        const NAME: &'static str = "Syphilm";
+    }
+);
+
+impl Syphilm {
+    pub fn init_with_data(data: &NSData) -> Id<Self> {
+        let syphilm_class = class!(Syphilm);
+        unsafe { msg_send_id![msg_send_id![syphilm_class, alloc], initWithData :data] }
+    }
+}
+
+extern_methods!(
+    unsafe impl Syphilm {
+        /// Replaces the published image with new PNG data.
+        ///
+        /// Declared by the Syphilm framework linked in `build.rs` as
+        /// `- (void)updateWithData:(NSData *)data`.
+        #[method(updateWithData:)]
+        pub fn update_with_data(&mut self, data: &NSData);
+    }
+);
+
+/// Publishes frames through a Syphilm instance.
+///
+/// The instance is owned by the thread that publishes new frames while the
+/// main thread runs the application.
+pub struct SyphilmSink(Id<Syphilm>);
+
+// SAFETY: The sink holds the only reference to its instance, which is created
+// in `new` and never cloned, and only messages it through `&mut self`, so the
+// instance is never used from two threads at once. Syphilm doesn't require
+// the main thread for `updateWithData:`: it only hands the image to its Syphon
+// server, which may publish from any thread, while the main thread runs the
+// application in `run_application`.
+unsafe impl Send for SyphilmSink {}
+
+impl SyphilmSink {
+    /// Creates a Syphilm output that starts out showing the given frame.
+    pub fn new(frame: &Frame) -> StrResult<Self> {
+        let data = encode_png(frame)?;
+        Ok(Self(Syphilm::init_with_data(&data)))
+    }
+}
+
+impl FrameSink for SyphilmSink {
+    fn publish(&mut self, frame: &Frame) -> io::Result<()> {
+        let data = encode_png(frame).map_err(|err| io::Error::other(err.as_str()))?;
+        self.0.update_with_data(&data);
+        Ok(())
+    }
+}
+
+/// Runs the application that serves the Syphilm outputs on the current
+/// thread until it terminates.
+pub fn run_application() -> StrResult<()> {
+    let Some(mtm) = MainThreadMarker::new() else {
+        bail!("Syphon outputs have to be run from the main thread");
+    };
+    let app = NSApplication::sharedApplication(mtm);
+    unsafe { app.run() };
+    Ok(())
+}
+
+/// Encodes a rendered frame into the PNG data Syphilm expects.
+fn encode_png(frame: &Frame) -> StrResult<Id<NSData>> {
+    let data: Vec<u8> = (0..frame.height)
+        .flat_map(|y| frame.row(y))
+        .copied()
+        .collect();
+    let png = PixmapRef::from_bytes(&data, frame.width, frame.height)
+        .ok_or("frame has an invalid size")?
+        .encode_png()
+        .map_err(|err| eco_format!("couldn't encode output to png ({err})"))?;
+    Ok(NSData::with_bytes(&png))
+}
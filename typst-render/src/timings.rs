@@ -0,0 +1,147 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use color_print::ceprintln;
+use ecow::eco_format;
+use typst::diag::StrResult;
+use typst::syntax::Span;
+use typst::World;
+
+use crate::args::ProcessArgs;
+use crate::world::SystemWorld;
+
+/// Records the timings of compilations and writes them as a Chrome trace.
+///
+/// The trace covers the configured number of compilations, including
+/// rendering and exporting. Once they are recorded, the trace is written and
+/// timing is switched off again.
+pub struct Timer {
+    /// Where to write the trace, while compilations are still recorded.
+    path: Option<PathBuf>,
+    /// The number of compilations to record.
+    compiles: usize,
+    /// The number of compilations recorded so far.
+    recorded: usize,
+}
+
+impl Timer {
+    pub fn new(args: &ProcessArgs) -> Self {
+        Self {
+            path: args.timings.clone(),
+            compiles: args.timings_compiles.map_or(1, |n| n.get()),
+            recorded: 0,
+        }
+    }
+
+    /// Runs `f` and records its timings if there are compilations left to
+    /// record.
+    pub fn record<T>(
+        &mut self,
+        world: &mut SystemWorld,
+        f: impl FnOnce(&mut SystemWorld) -> StrResult<T>,
+    ) -> StrResult<T> {
+        let Some(path) = &self.path else {
+            return f(world);
+        };
+
+        if self.recorded == 0 {
+            typst_timing::clear();
+        }
+
+        typst_timing::enable();
+        let output = f(world);
+        typst_timing::disable();
+        self.recorded += 1;
+
+        if self.recorded == self.compiles {
+            let file = File::create(path).map_err(|err| {
+                eco_format!("failed to create timings file {} ({err})", path.display())
+            })?;
+            let writer = BufWriter::with_capacity(1 << 20, file);
+            typst_timing::export_json(writer, |span| {
+                resolve_span(world, Span::from_raw(span))
+                    .unwrap_or_else(|| ("unknown".to_string(), 0))
+            })
+            .map_err(|err| eco_format!("failed to write timings ({err})"))?;
+
+            ceprintln!(
+                "<cyan,bold>timings</> of {} compilation(s) written to {}",
+                self.recorded,
+                path.display()
+            );
+            self.path = None;
+        }
+
+        output
+    }
+}
+
+/// Turns a span into a file name and line number for the trace.
+fn resolve_span(world: &SystemWorld, span: Span) -> Option<(String, u32)> {
+    let id = span.id()?;
+    let source = world.source(id).ok()?;
+    let range = source.range(span)?;
+    let line = source.byte_to_line(range.start)?;
+    Some((format!("{id:?}"), line as u32 + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::num::NonZeroUsize;
+
+    use typst::layout::PagedDocument;
+
+    use super::*;
+    use crate::args::{DiagnosticFormat, FontArgs, Input, WorldArgs};
+    use crate::compile::compile_document;
+
+    #[test]
+    fn writes_trace_after_recorded_compilations() {
+        let temp =
+            std::env::temp_dir().join(format!("typst-render-timings-{}", std::process::id()));
+        fs::create_dir_all(&temp).unwrap();
+        let main = temp.join("main.typ");
+        fs::write(&main, "#for i in range(3) [Page #i #pagebreak()]").unwrap();
+
+        let trace = temp.join("trace.json");
+        let process_args = ProcessArgs {
+            timings: Some(trace.clone()),
+            timings_compiles: NonZeroUsize::new(2),
+            ..ProcessArgs::default()
+        };
+        let world_args = WorldArgs {
+            root: Some(temp.clone()),
+            font: FontArgs {
+                ignore_system_fonts: true,
+                ..FontArgs::default()
+            },
+            ..WorldArgs::default()
+        };
+        let mut world = SystemWorld::new(&Input::Path(main), &world_args, &process_args).unwrap();
+        let mut timer = Timer::new(&process_args);
+        let mut compile = |timer: &mut Timer| {
+            timer
+                .record(&mut world, |world| {
+                    compile_document::<PagedDocument>(world, DiagnosticFormat::Short)
+                })
+                .unwrap();
+        };
+
+        compile(&mut timer);
+        assert!(!trace.exists(), "the trace was written too early");
+        compile(&mut timer);
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(&trace).unwrap()).unwrap();
+        let events = json.as_array().unwrap();
+        assert!(!events.is_empty());
+        assert!(events.iter().all(|event| event.get("ph").is_some()));
+
+        // Later compilations aren't recorded anymore.
+        fs::remove_file(&trace).unwrap();
+        compile(&mut timer);
+        assert!(!trace.exists());
+
+        fs::remove_dir_all(temp).ok();
+    }
+}
@@ -0,0 +1,86 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use color_print::ceprintln;
+use ecow::eco_format;
+use typst::diag::StrResult;
+use typst::layout::PagedDocument;
+
+use crate::args::VendorCommand;
+use crate::compile::compile_document;
+use crate::config::SimulationConfig;
+use crate::package::VENDOR_DIR;
+use crate::timings::Timer;
+use crate::world::SystemWorld;
+
+/// Execute a vendor command.
+///
+/// Compiles the document and copies every package it used into the vendor
+/// directory, using the same `namespace/name/version` layout as the package
+/// path so that the directory can serve as one.
+pub fn vendor(config: &SimulationConfig, command: &VendorCommand) -> StrResult<()> {
+    // Create the world that serves sources, files, and fonts.
+    let mut world = SystemWorld::new(&config.input, &config.world_config, &config.process_config)?;
+    let mut timer = Timer::new(&config.process_config);
+    timer.record(&mut world, |world| {
+        compile_document::<PagedDocument>(world, config.diagnostic_format())
+    })?;
+
+    let dir = command
+        .dir
+        .clone()
+        .unwrap_or_else(|| world.root().join(VENDOR_DIR));
+
+    let specs = world.used_packages();
+    if specs.is_empty() {
+        ceprintln!("<dim>the document doesn't use any packages</>");
+        return Ok(());
+    }
+
+    for spec in specs {
+        let source = world
+            .package_dir(&spec)
+            .map_err(|err| eco_format!("failed to prepare {spec} ({err})"))?;
+        let target = dir
+            .join(spec.namespace.as_str())
+            .join(spec.name.as_str())
+            .join(spec.version.to_string());
+
+        // Packages that are already vendored are served from the target.
+        if source == target {
+            ceprintln!("<dim>{} is already vendored</>", spec);
+            continue;
+        }
+
+        if target.exists() {
+            fs::remove_dir_all(&target)
+                .map_err(|err| eco_format!("failed to replace {} ({err})", target.display()))?;
+        }
+        copy_dir(&source, &target)
+            .map_err(|err| eco_format!("failed to copy {spec} to {} ({err})", target.display()))?;
+
+        ceprintln!(
+            "<green,bold>vendored</> {} <dim>into {}</>",
+            spec,
+            target.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Recursively copies a directory.
+fn copy_dir(source: &Path, target: &Path) -> io::Result<()> {
+    fs::create_dir_all(target)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let path = target.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &path)?;
+        } else {
+            fs::copy(entry.path(), path)?;
+        }
+    }
+    Ok(())
+}
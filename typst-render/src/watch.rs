@@ -0,0 +1,494 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use color_print::ceprintln;
+use ecow::eco_format;
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher as _};
+use typst::diag::{bail, StrResult};
+use typst::foundations::Value;
+
+use crate::args::Output;
+use crate::config::{ConfigSource, SimulationConfig};
+use crate::export::export_once;
+use crate::live;
+use crate::present::present;
+use crate::timings::Timer;
+use crate::world::SystemWorld;
+
+/// Execute a watching compilation command.
+///
+/// With an output, the document is exported again on every change. Without
+/// one, the rendered pages are published live. Changes to the config file are
+/// picked up as well. `config` is the configuration initially loaded from
+/// `source`.
+pub fn watch(mut config: SimulationConfig, source: ConfigSource) -> StrResult<()> {
+    // Create the world that serves sources, files, and fonts.
+    let mut world = SystemWorld::new(&config.input, &config.world_config, &config.process_config)?;
+
+    match &config.output {
+        None => return present(world, config, source),
+        Some(Output::Stdout) => bail!("cannot write document to stdout in watch mode"),
+        Some(Output::Path(_)) => {}
+    }
+
+    // Perform initial compilation.
+    let mut timer = Timer::new(&config.process_config);
+    let start = Instant::now();
+    let result = timer.record(&mut world, |world| export_once(world, &config));
+    log_compilation(&world, start, &result, None);
+
+    watch_world(&mut world, &mut config, &source, &mut timer, export_once)
+}
+
+/// Runs `recompile` whenever one of the world's dependencies, the config file
+/// or a live input changes.
+///
+/// The world is expected to have been compiled once already, so that its
+/// dependencies are known when the first watches are set up. Failed
+/// compilations are only logged, so whatever the last good compilation
+/// produced stays in place until the errors are fixed. The `timer` records
+/// the recompilations it has left.
+pub fn watch_world(
+    world: &mut SystemWorld,
+    config: &mut SimulationConfig,
+    source: &ConfigSource,
+    timer: &mut Timer,
+    mut recompile: impl FnMut(&mut SystemWorld, &SimulationConfig) -> StrResult<()>,
+) -> StrResult<()> {
+    // Create a file system watcher.
+    let mut watcher = Watcher::new()?;
+
+    // Events report absolute paths, so the config and input files are
+    // compared by their canonical paths.
+    let config_path = source
+        .path
+        .as_ref()
+        .and_then(|path| path.canonicalize().ok());
+    let mut input_file = canonical_input_file(config);
+
+    // Watch all dependencies of the initial compilation.
+    watcher.update(
+        world
+            .dependencies()
+            .chain(config_path.clone())
+            .chain(input_file.clone()),
+    )?;
+
+    // Receive live inputs, which are kept across config reloads.
+    live::subscribe(&config.live_config, watcher.sender())?;
+    let mut live_inputs = BTreeMap::new();
+
+    // Recompile whenever something relevant happens.
+    loop {
+        // Wait until anything relevant happens.
+        let changes = watcher.wait()?;
+
+        // Pick up a changed config or input file before recompiling.
+        let reloaded = [&config_path, &input_file]
+            .into_iter()
+            .flatten()
+            .any(|path| changes.paths.contains(path));
+        if reloaded {
+            reload_config(world, config, source);
+            input_file = canonical_input_file(config);
+        }
+
+        // Apply live inputs on top of the configured ones.
+        if !changes.inputs.is_empty() || (reloaded && !live_inputs.is_empty()) {
+            live_inputs.extend(changes.inputs.iter().cloned());
+            let mut world_args = config.world_config.clone();
+            world_args.inputs.extend(live_inputs.clone());
+            world.reconfigure(&world_args, &config.process_config);
+        }
+
+        // Reset all dependencies.
+        world.reset();
+
+        // Recompile.
+        let start = Instant::now();
+        let result = timer.record(world, |world| recompile(world, config));
+        log_compilation(world, start, &result, Some(&changes));
+
+        // Evict the cache.
+        comemo::evict(10);
+
+        // Adjust the file watching.
+        watcher.update(
+            world
+                .dependencies()
+                .chain(config_path.clone())
+                .chain(input_file.clone()),
+        )?;
+    }
+}
+
+/// The canonical path of the configured input file, if any.
+fn canonical_input_file(config: &SimulationConfig) -> Option<PathBuf> {
+    config
+        .world_config
+        .input_file
+        .as_ref()
+        .and_then(|path| path.canonicalize().ok())
+}
+
+/// Loads the config file again and applies it.
+///
+/// The world is only created anew if the input, root, fonts or package paths
+/// changed; everything else is applied in place. If the new config can't be
+/// loaded, the last valid one stays in use.
+fn reload_config(world: &mut SystemWorld, config: &mut SimulationConfig, source: &ConfigSource) {
+    let new = match source.load() {
+        Ok(new) => new,
+        Err(err) => {
+            ceprintln!(
+                "<red,bold>error</>: {} <dim>(keeping the last valid config)</>",
+                err
+            );
+            return;
+        }
+    };
+
+    if new.needs_new_world(config) {
+        match SystemWorld::new(&new.input, &new.world_config, &new.process_config) {
+            Ok(new_world) => *world = new_world,
+            Err(err) => {
+                ceprintln!(
+                    "<red,bold>error</>: {} <dim>(keeping the last valid config)</>",
+                    err
+                );
+                return;
+            }
+        }
+        ceprintln!("<cyan,bold>reloaded</> config and recreated the world");
+    } else {
+        world.reconfigure(&new.world_config, &new.process_config);
+        ceprintln!("<cyan,bold>reloaded</> config");
+    }
+
+    *config = new;
+}
+
+/// Prints one line about the outcome of a compilation.
+pub fn log_compilation(
+    world: &SystemWorld,
+    start: Instant,
+    result: &StrResult<()>,
+    changes: Option<&Changes>,
+) {
+    let changes = changes
+        .map(|changes| format!(" ({} changed)", describe_changes(changes)))
+        .unwrap_or_default();
+
+    match result {
+        Ok(()) => ceprintln!(
+            "<green,bold>compiled</> {} in {:.2?}<dim>{}</>",
+            world.main().vpath().as_rootless_path().display(),
+            start.elapsed(),
+            changes,
+        ),
+        Err(err) => ceprintln!(
+            "<red,bold>compilation failed</> after {:.2?}<dim>{}</>: {} <dim>(keeping the last output)</>",
+            start.elapsed(),
+            changes,
+            err,
+        ),
+    }
+}
+
+/// Summarizes what triggered a recompilation for the log line.
+fn describe_changes(changes: &Changes) -> String {
+    let mut parts = vec![];
+    match changes.paths.as_slice() {
+        [] => {}
+        [path] => parts.push(
+            path.file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.display().to_string()),
+        ),
+        paths => parts.push(format!("{} files", paths.len())),
+    }
+    match changes.inputs.as_slice() {
+        [] => {}
+        [(key, _)] => parts.push(format!("input {key}")),
+        inputs => parts.push(format!("{} inputs", inputs.len())),
+    }
+
+    if parts.is_empty() {
+        "nothing".into()
+    } else {
+        parts.join(", ")
+    }
+}
+
+/// Something that may require a recompilation.
+pub enum Signal {
+    /// A file system event.
+    Fs(notify::Result<Event>),
+    /// Live inputs changed to the given values.
+    Inputs(Vec<(String, Value)>),
+}
+
+/// Everything that changed in one batch of signals.
+#[derive(Debug, Default)]
+pub struct Changes {
+    /// The paths that changed on disk.
+    pub paths: Vec<PathBuf>,
+    /// The live inputs that changed, with their new values.
+    pub inputs: Vec<(String, Value)>,
+}
+
+impl Changes {
+    /// Records changed live inputs, keeping only the newest value per key.
+    fn add_inputs(&mut self, inputs: Vec<(String, Value)>) {
+        for (key, value) in inputs {
+            match self.inputs.iter_mut().find(|(k, _)| *k == key) {
+                Some(entry) => entry.1 = value,
+                None => self.inputs.push((key, value)),
+            }
+        }
+    }
+}
+
+/// Watches file system activity and live inputs.
+struct Watcher {
+    /// The underlying watcher.
+    watcher: RecommendedWatcher,
+    /// Receives file system events and live inputs.
+    rx: Receiver<Signal>,
+    /// Hands out senders for live inputs.
+    tx: Sender<Signal>,
+    /// Keeps track of which paths are watched via `watcher`. The boolean is
+    /// used during updating for mark-and-sweep garbage collection of paths we
+    /// should unwatch.
+    watched: HashMap<PathBuf, bool>,
+}
+
+impl Watcher {
+    /// How long to wait for a shortly following file system event when
+    /// watching.
+    const BATCH_TIMEOUT: Duration = Duration::from_millis(100);
+
+    /// The maximum time we spend batching events before quitting wait().
+    const STARVE_TIMEOUT: Duration = Duration::from_millis(500);
+
+    /// The interval in which we poll when falling back to poll watching
+    /// due to missing events.
+    const POLL_INTERVAL: Duration = Duration::from_millis(300);
+
+    /// Create a new, blank watcher.
+    fn new() -> StrResult<Self> {
+        // Setup file watching.
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        // Set the poll interval to something more eager than the default.
+        // That default seems a bit excessive for our purposes at around 30s.
+        // Depending on feedback, some tuning might still be in order. Note that
+        // this only affects a tiny number of systems. Most do not use the
+        // [`notify::PollWatcher`].
+        let config = notify::Config::default().with_poll_interval(Self::POLL_INTERVAL);
+        let fs_tx = tx.clone();
+        let watcher = RecommendedWatcher::new(
+            move |event| {
+                fs_tx.send(Signal::Fs(event)).ok();
+            },
+            config,
+        )
+        .map_err(|err| eco_format!("failed to setup file watching ({err})"))?;
+
+        Ok(Self {
+            rx,
+            tx,
+            watcher,
+            watched: HashMap::new(),
+        })
+    }
+
+    /// Returns a sender through which live inputs wake the watcher up.
+    fn sender(&self) -> Sender<Signal> {
+        self.tx.clone()
+    }
+
+    /// Update the watching to watch exactly the listed files.
+    ///
+    /// Files that are not yet watched will be watched. Files that are already
+    /// watched, but don't need to be watched anymore, will be unwatched.
+    fn update(&mut self, iter: impl IntoIterator<Item = PathBuf>) -> StrResult<()> {
+        // Mark all files as not "seen" so that we may unwatch them if they
+        // aren't in the dependency list.
+        for seen in self.watched.values_mut() {
+            *seen = false;
+        }
+
+        // Reconfigure the watches.
+        for path in iter {
+            match self.watched.entry(path) {
+                Entry::Occupied(mut entry) => {
+                    entry.insert(true);
+                }
+                Entry::Vacant(entry) => {
+                    self.watcher
+                        .watch(entry.key(), RecursiveMode::NonRecursive)
+                        .map_err(|err| eco_format!("failed to watch {:?} ({err})", entry.key()))?;
+                    entry.insert(true);
+                }
+            }
+        }
+
+        // Remove old watches.
+        self.watched.retain(|path, &mut seen| {
+            if !seen {
+                self.watcher.unwatch(path).ok();
+            }
+            seen
+        });
+
+        Ok(())
+    }
+
+    /// Wait until there is a change to a watched path or a live input and
+    /// return what changed in the batch.
+    fn wait(&mut self) -> StrResult<Changes> {
+        loop {
+            // Wait for an initial event. If there are no dependencies, we'll
+            // just wait forever.
+            let mut changes = Changes::default();
+            match self
+                .rx
+                .recv()
+                .map_err(|err| eco_format!("failed to receive event ({err})"))?
+            {
+                Signal::Fs(event) => {
+                    let event =
+                        event.map_err(|err| eco_format!("failed to watch dependencies ({err})"))?;
+
+                    // Only consider relevant events.
+                    if !is_relevant_event_kind(&event.kind) {
+                        continue;
+                    }
+                    self.record(event, &mut changes.paths);
+                }
+                Signal::Inputs(inputs) => changes.add_inputs(inputs),
+            }
+
+            // Wait a small window of time for events that directly follow,
+            // so that editors writing a file in several steps or tools
+            // touching many files at once only trigger one recompilation.
+            let batch_start = Instant::now();
+            while batch_start.elapsed() < Self::STARVE_TIMEOUT {
+                let Ok(signal) = self.rx.recv_timeout(Self::BATCH_TIMEOUT) else {
+                    break;
+                };
+                match signal {
+                    Signal::Fs(Ok(event)) if is_relevant_event_kind(&event.kind) => {
+                        self.record(event, &mut changes.paths);
+                    }
+                    Signal::Fs(_) => {}
+                    Signal::Inputs(inputs) => changes.add_inputs(inputs),
+                }
+            }
+
+            return Ok(changes);
+        }
+    }
+
+    /// Collect the paths of a relevant event.
+    fn record(&mut self, event: Event, changed: &mut Vec<PathBuf>) {
+        // Workaround for notify-rs' implicit unwatch on remove/rename
+        // (triggered by some editors when saving files) with the inotify
+        // backend. By keeping track of the potentially unwatched files, we
+        // can allow those we still depend on to be watched again later on.
+        if matches!(
+            event.kind,
+            notify::EventKind::Remove(notify::event::RemoveKind::File)
+                | notify::EventKind::Modify(notify::event::ModifyKind::Name(
+                    notify::event::RenameMode::From
+                ))
+        ) {
+            for path in &event.paths {
+                // Remove the watch in case it still exists.
+                self.watcher.unwatch(path).ok();
+                self.watched.remove(path);
+            }
+        }
+
+        for path in event.paths {
+            if !changed.contains(&path) {
+                changed.push(path);
+            }
+        }
+    }
+}
+
+/// Whether a kind of watch event is relevant for compilation.
+fn is_relevant_event_kind(kind: &notify::EventKind) -> bool {
+    match kind {
+        notify::EventKind::Any => true,
+        notify::EventKind::Access(_) => false,
+        notify::EventKind::Create(_) => true,
+        notify::EventKind::Modify(kind) => match kind {
+            notify::event::ModifyKind::Any => true,
+            notify::event::ModifyKind::Data(_) => true,
+            notify::event::ModifyKind::Metadata(_) => false,
+            notify::event::ModifyKind::Name(_) => true,
+            notify::event::ModifyKind::Other => false,
+        },
+        notify::EventKind::Remove(_) => true,
+        notify::EventKind::Other => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn keeps_last_valid_config_on_reload() {
+        let temp = std::env::temp_dir().join(format!("typst-render-reload-{}", std::process::id()));
+        fs::create_dir_all(temp.join("other")).unwrap();
+        fs::write(temp.join("main.typ"), "Hello").unwrap();
+        fs::write(temp.join("other/main.typ"), "Hello").unwrap();
+        let config_path = temp.join("config.toml");
+        let write_config = |root: &str, ppi: &str| {
+            let config = format!(
+                "input = '{}'\nppi = {ppi}\n\
+                 [world_config]\nroot = '{}'\n\
+                 [world_config.font]\nignore_system_fonts = true\n",
+                temp.join(root).join("main.typ").display(),
+                temp.join(root).display(),
+            );
+            fs::write(&config_path, config).unwrap();
+        };
+
+        write_config(".", "72");
+        let source = ConfigSource {
+            path: Some(config_path.clone()),
+            args: None,
+        };
+        let mut config = source.load().unwrap();
+        let mut world =
+            SystemWorld::new(&config.input, &config.world_config, &config.process_config).unwrap();
+
+        // A broken config keeps the last valid one.
+        write_config(".", "\"many\"");
+        reload_config(&mut world, &mut config, &source);
+        assert_eq!(config.ppi, Some(72.0));
+
+        // Other changes are applied in place.
+        write_config(".", "96");
+        reload_config(&mut world, &mut config, &source);
+        assert_eq!(config.ppi, Some(96.0));
+        assert_eq!(world.root(), temp.canonicalize().unwrap());
+
+        // A new root needs a new world.
+        write_config("other", "96");
+        reload_config(&mut world, &mut config, &source);
+        assert_eq!(world.root(), temp.join("other").canonicalize().unwrap());
+
+        fs::remove_dir_all(temp).ok();
+    }
+}
@@ -1,25 +1,26 @@
 use std::collections::HashMap;
 use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::sync::{LazyLock, OnceLock};
+use std::sync::{Arc, LazyLock, OnceLock};
 use std::{fmt, fs, io, mem};
 
 use chrono::{DateTime, Datelike, FixedOffset, Local, Utc};
+use codespan_reporting::files::Error as CodespanError;
 use ecow::{eco_format, EcoString};
 use parking_lot::Mutex;
-use typst::diag::{FileError, FileResult};
-use typst::foundations::{Bytes, Datetime, Dict, IntoValue};
+use typst::diag::{FileError, FileResult, PackageResult};
+use typst::foundations::{Bytes, Datetime, Dict};
+use typst::syntax::package::PackageSpec;
 use typst::syntax::{FileId, Source, VirtualPath};
 use typst::text::{Font, FontBook};
 use typst::utils::LazyHash;
 use typst::{Library, World};
 use typst_kit::fonts::{FontSlot, Fonts};
-use typst_kit::package::PackageStorage;
 use typst_timing::timed;
 
 use crate::args::{Feature, Input, ProcessArgs, WorldArgs};
-use crate::download::PrintDownload;
-use crate::package;
+use crate::download::{DownloadEvent, DownloadListener};
+use crate::package::Packages;
 
 /// Static `FileId` allocated for stdin.
 /// This is to ensure that a file is read in the correct way.
@@ -41,12 +42,18 @@ pub struct SystemWorld {
     fonts: Vec<FontSlot>,
     /// Maps file ids to source files and buffers.
     slots: Mutex<HashMap<FileId, FileSlot>>,
-    /// Holds information about where packages are stored.
-    package_storage: PackageStorage,
+    /// Contents of virtual files, which shadow the files on disk.
+    overlay: HashMap<FileId, Vec<u8>>,
+    /// Holds information about where packages are stored and reports
+    /// download progress.
+    packages: Packages,
     /// The current datetime if requested. This is stored here to ensure it is
     /// always the same within one compilation.
     /// Reset between compilations if not [`Now::Fixed`].
     now: Now,
+    /// The UTC offset used when the document doesn't request one. `None`
+    /// means the local time zone.
+    utc_offset: Option<FixedOffset>,
 }
 
 impl SystemWorld {
@@ -97,44 +104,36 @@ impl SystemWorld {
             *STDIN_ID
         };
 
-        let library = {
-            // Convert the input pairs to a dictionary.
-            let inputs: Dict = world_args
-                .inputs
-                .iter()
-                .map(|(k, v)| (k.as_str().into(), v.as_str().into_value()))
-                .collect();
-
-            // let features = process_args
-            //     .features
-            //     .iter()
-            //     .map(|&feature| match feature {
-            //         Feature::Html => typst::Feature::Html,
-            //     })
-            //     .collect();
-
-            Library::builder().with_inputs(inputs).build()
-        };
-
         let fonts = Fonts::searcher()
             .include_system_fonts(!world_args.font.ignore_system_fonts)
             .search_with(&world_args.font.font_paths);
 
-        let now = Now::System(OnceLock::new());
-
         Ok(Self {
             workdir: std::env::current_dir().ok(),
             root,
             main,
-            library: LazyHash::new(library),
+            library: LazyHash::new(library(world_args, process_args)),
             book: LazyHash::new(fonts.book),
             fonts: fonts.fonts,
             slots: Mutex::new(HashMap::new()),
-            package_storage: package::storage(&world_args.package),
-            now,
+            overlay: HashMap::new(),
+            packages: Packages::new(&world_args.package, &root),
+            now: now(world_args),
+            utc_offset: world_args.utc_offset,
         })
     }
 
+    /// Applies changed arguments that don't require a new world, such as
+    /// `sys.inputs` or the pinned date.
+    ///
+    /// Changes to the root, fonts or package paths are not picked up; the world
+    /// has to be created anew for those.
+    pub fn reconfigure(&mut self, world_args: &WorldArgs, process_args: &ProcessArgs) {
+        self.library = LazyHash::new(library(world_args, process_args));
+        self.now = now(world_args);
+        self.utc_offset = world_args.utc_offset;
+    }
+
     /// The id of the main source file.
     pub fn main(&self) -> FileId {
         self.main
@@ -150,13 +149,63 @@ impl SystemWorld {
         self.workdir.as_deref().unwrap_or(Path::new("."))
     }
 
+    /// Replaces the listener that receives the events of package downloads,
+    /// which prints them to standard error by default.
+    pub fn set_download_listener(
+        &mut self,
+        listener: impl Fn(&DownloadEvent) + Send + Sync + 'static,
+    ) {
+        let listener: DownloadListener = Arc::new(listener);
+        self.packages.set_listener(listener);
+    }
+
+    /// Sets the contents of a virtual file, which shadows the file at the same
+    /// path on disk, if any, until it's removed again.
+    ///
+    /// The path is resolved within the project root like paths in documents
+    /// are. Only documents that depend on the file are recompiled, as long as
+    /// the contents actually changed.
+    pub fn set_virtual_file(&mut self, path: impl AsRef<Path>, data: impl Into<Vec<u8>>) -> FileId {
+        let id = FileId::new(None, VirtualPath::new(path));
+        self.overlay.insert(id, data.into());
+        id
+    }
+
+    /// Removes a virtual file, uncovering the file on disk, if any. Returns
+    /// whether the file existed.
+    pub fn remove_virtual_file(&mut self, path: impl AsRef<Path>) -> bool {
+        let id = FileId::new(None, VirtualPath::new(path));
+        self.overlay.remove(&id).is_some()
+    }
+
     /// Return all paths the last compilation depended on.
+    ///
+    /// Virtual files are left out because they don't exist on disk.
     pub fn dependencies(&mut self) -> impl Iterator<Item = PathBuf> + '_ {
         self.slots
+            .get_mut()
+            .values()
+            .filter(|slot| slot.accessed() && !self.overlay.contains_key(&slot.id))
+            .filter_map(|slot| system_path(&self.root, slot.id, &self.packages).ok())
+    }
+
+    /// Return all packages the last compilation used.
+    pub fn used_packages(&mut self) -> Vec<PackageSpec> {
+        let mut specs: Vec<PackageSpec> = self
+            .slots
             .get_mut()
             .values()
             .filter(|slot| slot.accessed())
-            .filter_map(|slot| system_path(&self.root, slot.id, &self.package_storage).ok())
+            .filter_map(|slot| slot.id.package().cloned())
+            .collect();
+        specs.sort_by_cached_key(|spec| spec.to_string());
+        specs.dedup();
+        specs
+    }
+
+    /// Return the directory of a package, downloading it if necessary.
+    pub fn package_dir(&self, spec: &PackageSpec) -> PackageResult<PathBuf> {
+        self.packages.prepare(spec)
     }
 
     /// Reset the compilation state in preparation of a new compilation.
@@ -177,6 +226,38 @@ impl SystemWorld {
     }
 }
 
+/// Builds the standard library with the given inputs and features.
+fn library(world_args: &WorldArgs, process_args: &ProcessArgs) -> Library {
+    // Convert the input pairs to a dictionary. Later pairs override earlier
+    // ones with the same key.
+    let inputs: Dict = world_args
+        .inputs
+        .iter()
+        .map(|(k, v)| (k.as_str().into(), v.clone()))
+        .collect();
+
+    let features = process_args
+        .features
+        .iter()
+        .map(|&feature| match feature {
+            Feature::Html => typst::Feature::Html,
+        })
+        .collect();
+
+    Library::builder()
+        .with_inputs(inputs)
+        .with_features(features)
+        .build()
+}
+
+/// Determines the clock from the pinned creation timestamp, if any.
+fn now(world_args: &WorldArgs) -> Now {
+    match world_args.creation_timestamp {
+        Some(time) => Now::Fixed(time),
+        None => Now::System(OnceLock::new()),
+    }
+}
+
 impl World for SystemWorld {
     fn library(&self) -> &LazyHash<Library> {
         &self.library
@@ -191,11 +272,15 @@ impl World for SystemWorld {
     }
 
     fn source(&self, id: FileId) -> FileResult<Source> {
-        self.slot(id, |slot| slot.source(&self.root, &self.package_storage))
+        self.slot(id, |slot| {
+            slot.source(&self.root, &self.packages, &self.overlay)
+        })
     }
 
     fn file(&self, id: FileId) -> FileResult<Bytes> {
-        self.slot(id, |slot| slot.file(&self.root, &self.package_storage))
+        self.slot(id, |slot| {
+            slot.file(&self.root, &self.packages, &self.overlay)
+        })
     }
 
     fn font(&self, index: usize) -> Option<Font> {
@@ -208,9 +293,13 @@ impl World for SystemWorld {
             Now::System(time) => time.get_or_init(Utc::now),
         };
 
-        // The time with the specified UTC offset, or within the local time zone.
+        // The time with the specified UTC offset, or within the configured
+        // default offset or local time zone.
         let with_offset = match offset {
-            None => now.with_timezone(&Local).fixed_offset(),
+            None => match self.utc_offset {
+                Some(offset) => now.with_timezone(&offset),
+                None => now.with_timezone(&Local).fixed_offset(),
+            },
             Some(hours) => {
                 let seconds = i32::try_from(hours).ok()?.checked_mul(3600)?;
                 now.with_timezone(&FixedOffset::east_opt(seconds)?)
@@ -225,6 +314,68 @@ impl World for SystemWorld {
     }
 }
 
+impl<'a> codespan_reporting::files::Files<'a> for SystemWorld {
+    type FileId = FileId;
+    type Name = String;
+    type Source = Source;
+
+    fn name(&'a self, id: FileId) -> Result<Self::Name, CodespanError> {
+        let vpath = id.vpath();
+        Ok(if let Some(package) = id.package() {
+            format!("{package}{}", vpath.as_rooted_path().display())
+        } else {
+            // Try to express the path relative to the working directory.
+            vpath
+                .resolve(self.root())
+                .and_then(|abs| pathdiff::diff_paths(abs, self.workdir()))
+                .as_deref()
+                .unwrap_or_else(|| vpath.as_rootless_path())
+                .to_string_lossy()
+                .into()
+        })
+    }
+
+    fn source(&'a self, id: FileId) -> Result<Self::Source, CodespanError> {
+        Ok(self.lookup(id))
+    }
+
+    fn line_index(&'a self, id: FileId, given: usize) -> Result<usize, CodespanError> {
+        let source = self.lookup(id);
+        source
+            .byte_to_line(given)
+            .ok_or_else(|| CodespanError::IndexTooLarge {
+                given,
+                max: source.len_bytes(),
+            })
+    }
+
+    fn line_range(
+        &'a self,
+        id: FileId,
+        given: usize,
+    ) -> Result<std::ops::Range<usize>, CodespanError> {
+        let source = self.lookup(id);
+        source
+            .line_to_range(given)
+            .ok_or_else(|| CodespanError::LineTooLarge {
+                given,
+                max: source.len_lines(),
+            })
+    }
+
+    fn column_number(&'a self, id: FileId, _: usize, given: usize) -> Result<usize, CodespanError> {
+        let source = self.lookup(id);
+        source.byte_to_column(given).ok_or_else(|| {
+            let max = source.len_bytes();
+            if given <= max {
+                CodespanError::InvalidCharBoundary { given }
+            } else {
+                CodespanError::IndexTooLarge { given, max }
+            }
+        })
+    }
+}
+
 impl SystemWorld {
     /// Access the canonical slot for the given file id.
     fn slot<F, T>(&self, id: FileId, f: F) -> T
@@ -274,10 +425,11 @@ impl FileSlot {
     fn source(
         &mut self,
         project_root: &Path,
-        package_storage: &PackageStorage,
+        packages: &Packages,
+        overlay: &HashMap<FileId, Vec<u8>>,
     ) -> FileResult<Source> {
         self.source.get_or_init(
-            || read(self.id, project_root, package_storage),
+            || read(self.id, project_root, packages, overlay),
             |data, prev| {
                 let text = decode_utf8(&data)?;
                 if let Some(mut prev) = prev {
@@ -291,10 +443,15 @@ impl FileSlot {
     }
 
     /// Retrieve the file's bytes.
-    fn file(&mut self, project_root: &Path, package_storage: &PackageStorage) -> FileResult<Bytes> {
+    fn file(
+        &mut self,
+        project_root: &Path,
+        packages: &Packages,
+        overlay: &HashMap<FileId, Vec<u8>>,
+    ) -> FileResult<Bytes> {
         self.file.get_or_init(
-            || read(self.id, project_root, package_storage),
-            |data, _| Ok(data.into()),
+            || read(self.id, project_root, packages, overlay),
+            |data, _| Ok(Bytes::new(data)),
         )
     }
 }
@@ -364,17 +521,13 @@ impl<T: Clone> SlotCell<T> {
 
 /// Resolves the path of a file id on the system, downloading a package if
 /// necessary.
-fn system_path(
-    project_root: &Path,
-    id: FileId,
-    package_storage: &PackageStorage,
-) -> FileResult<PathBuf> {
+fn system_path(project_root: &Path, id: FileId, packages: &Packages) -> FileResult<PathBuf> {
     // Determine the root path relative to which the file path
     // will be resolved.
     let buf;
     let mut root = project_root;
     if let Some(spec) = id.package() {
-        buf = package_storage.prepare_package(spec, &mut PrintDownload(&spec))?;
+        buf = packages.prepare(spec)?;
         root = &buf;
     }
 
@@ -385,13 +538,21 @@ fn system_path(
 
 /// Reads a file from a `FileId`.
 ///
-/// If the ID represents stdin it will read from standard input,
-/// otherwise it gets the file path of the ID and reads the file from disk.
-fn read(id: FileId, project_root: &Path, package_storage: &PackageStorage) -> FileResult<Vec<u8>> {
-    if id == *STDIN_ID {
+/// Virtual files take precedence. If the ID represents stdin it will read
+/// from standard input, otherwise it gets the file path of the ID and reads
+/// the file from disk.
+fn read(
+    id: FileId,
+    project_root: &Path,
+    packages: &Packages,
+    overlay: &HashMap<FileId, Vec<u8>>,
+) -> FileResult<Vec<u8>> {
+    if let Some(data) = overlay.get(&id) {
+        Ok(data.clone())
+    } else if id == *STDIN_ID {
         read_from_stdin()
     } else {
-        read_from_disk(&system_path(project_root, id, package_storage)?)
+        read_from_disk(&system_path(project_root, id, packages)?)
     }
 }
 
@@ -427,8 +588,8 @@ fn decode_utf8(buf: &[u8]) -> FileResult<&str> {
 
 /// The current date and time.
 enum Now {
-    /// The date and time if the environment `SOURCE_DATE_EPOCH` is set.
-    /// Used for reproducible builds.
+    /// The date and time if a creation timestamp is configured or the
+    /// environment `SOURCE_DATE_EPOCH` is set. Used for reproducible builds.
     Fixed(DateTime<Utc>),
     /// The current date and time if the time is not externally fixed.
     System(OnceLock<DateTime<Utc>>),
@@ -473,3 +634,61 @@ impl From<WorldCreationError> for EcoString {
         eco_format!("{err}")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::args::FontArgs;
+
+    use super::*;
+
+    #[test]
+    fn virtual_files_shadow_disk_files() {
+        let temp =
+            std::env::temp_dir().join(format!("typst-render-overlay-{}", std::process::id()));
+        fs::remove_dir_all(&temp).ok();
+        fs::create_dir_all(&temp).unwrap();
+        fs::write(temp.join("main.typ"), "#read(\"data.json\")").unwrap();
+        fs::write(temp.join("data.json"), "disk").unwrap();
+
+        let world_args = WorldArgs {
+            root: Some(temp.clone()),
+            font: FontArgs {
+                ignore_system_fonts: true,
+                ..FontArgs::default()
+            },
+            ..WorldArgs::default()
+        };
+        let input = Input::Path(temp.join("main.typ"));
+        let mut world = SystemWorld::new(&input, &world_args, &ProcessArgs::default()).unwrap();
+        let id = FileId::new(None, VirtualPath::new("data.json"));
+        let fingerprint = |world: &SystemWorld| world.slots.lock()[&id].file.fingerprint;
+
+        assert_eq!(world.file(id).unwrap().as_slice(), b"disk");
+        let on_disk = fingerprint(&world);
+        assert!(world.dependencies().any(|path| path.ends_with("data.json")));
+
+        // The virtual file shadows the one on disk and changes the
+        // fingerprint, so that dependents are compiled again.
+        world.reset();
+        assert_eq!(world.set_virtual_file("data.json", "virtual"), id);
+        assert_eq!(world.file(id).unwrap().as_slice(), b"virtual");
+        let shadowed = fingerprint(&world);
+        assert_ne!(shadowed, on_disk);
+        assert!(!world.dependencies().any(|path| path.ends_with("data.json")));
+
+        // Setting the same contents again keeps the fingerprint.
+        world.reset();
+        world.set_virtual_file("data.json", "virtual");
+        world.file(id).unwrap();
+        assert_eq!(fingerprint(&world), shadowed);
+
+        // Removing the virtual file uncovers the one on disk.
+        world.reset();
+        assert!(world.remove_virtual_file("data.json"));
+        assert!(!world.remove_virtual_file("data.json"));
+        assert_eq!(world.file(id).unwrap().as_slice(), b"disk");
+        assert_eq!(fingerprint(&world), on_disk);
+
+        fs::remove_dir_all(temp).ok();
+    }
+}